@@ -0,0 +1,149 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+
+/// Distinguishes temporary files created by the same process
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file being written next to the file it will replace. It is removed when
+/// dropped, unless `commit` moved it into place.
+pub(crate) struct TempFile {
+    path: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl TempFile {
+    /// Reserve a hidden temporary path in the same directory as `target` (so the
+    /// final rename stays on one filesystem), keeping its extension, which
+    /// tools like ffmpeg use to pick the output format. A symlinked target is
+    /// resolved so the link itself is left alone.
+    pub(crate) fn new(target: &Path) -> TempFile {
+        let target = fs::canonicalize(target).unwrap_or_else(|_| target.to_path_buf());
+        let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!(".{}.{}-{}.tmp", stem, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Some(extension) = target.extension() {
+            name = format!("{}.{}", name, extension.to_string_lossy());
+        }
+        TempFile { path: target.with_file_name(name), target, committed: false }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush the temporary file to disk and rename it over the target, keeping
+    /// the target's permissions if it already exists
+    pub(crate) fn commit(mut self) -> Result<()> {
+        let file = File::options().write(true).open(&self.path).map_err(Error::io(&self.path))?;
+        if let Ok(metadata) = fs::metadata(&self.target) {
+            file.set_permissions(metadata.permissions()).map_err(Error::io(&self.path))?;
+        }
+        file.sync_all().map_err(Error::io(&self.path))?;
+        drop(file);
+
+        fs::rename(&self.path, &self.target).map_err(Error::io(&self.target))?;
+        self.committed = true;
+        sync_dir(&self.target).map_err(Error::io(&self.target))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Make the rename durable by syncing the directory entry
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Replace `path` with `head` followed by everything in the current file from
+/// `tail_offset` on, without ever leaving a partly written file at `path`
+pub(crate) fn replace(path: &Path, head: &[u8], tail_offset: u64) -> Result<()> {
+    replace_range(path, head, tail_offset..u64::MAX)
+}
+
+/// Like `replace`, but keep only the `tail` byte range of the current file
+/// after `head`, e.g. to drop a trailer
+pub(crate) fn replace_range(path: &Path, head: &[u8], tail: Range<u64>) -> Result<()> {
+    let temp = TempFile::new(path);
+    let mut source = File::open(path).map_err(Error::io(path))?;
+    let mut output = File::create(temp.path()).map_err(Error::io(temp.path()))?;
+    output.write_all(head).map_err(Error::io(temp.path()))?;
+    source.seek(SeekFrom::Start(tail.start))
+        .and_then(|_| io::copy(&mut (&mut source).take(tail.end.saturating_sub(tail.start)), &mut output))
+        .map_err(Error::io(path))?;
+    drop(output);
+    temp.commit()
+}
+
+/// Write `contents` to `path`, without ever leaving a partly written file there
+pub(crate) fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp = TempFile::new(path);
+    fs::write(temp.path(), contents).map_err(Error::io(temp.path()))?;
+    temp.commit()
+}
+
+/// Replace `path` with a copy of `source`, without ever leaving a partly
+/// written file at `path`
+pub(crate) fn copy(source: &Path, path: &Path) -> Result<()> {
+    let temp = TempFile::new(path);
+    fs::copy(source, temp.path()).map_err(Error::io(source))?;
+    temp.commit()
+}
+
+/// Move a file, copying it when `to` is on another filesystem
+pub(crate) fn rename(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy(from, to)?;
+            fs::remove_file(from).map_err(Error::io(from))
+        }
+        result => result.map_err(Error::io(from)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_and_temp_cleanup() {
+        let dir = std::env::temp_dir().join(format!("audio-metadata-atomic-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.mp3");
+        fs::write(&path, b"OLDHEADaudio").unwrap();
+
+        replace(&path, b"NEW", 7).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"NEWaudio");
+
+        // An abandoned temporary file is cleaned up and the target is untouched
+        let temp = TempFile::new(&path);
+        assert_eq!(temp.path().extension().unwrap(), "mp3");
+        fs::write(temp.path(), b"partial").unwrap();
+        let temp_path = temp.path().to_path_buf();
+        drop(temp);
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"NEWaudio");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
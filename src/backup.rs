@@ -0,0 +1,642 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::atomic;
+use crate::error::{Error, ParseError, Result};
+
+/// Name of the manifest file in each backup run directory
+pub const MANIFEST_NAME: &str = "manifest.jsonl";
+
+/// Prefix of backup run directory names
+const RUN_PREFIX: &str = "audio-metadata-";
+
+/// Directory that backup runs are created in by default:
+/// `$XDG_STATE_HOME/audio-metadata/backups` (`~/.local/state/...` if unset),
+/// or the system temporary directory when there is no home directory
+pub fn default_root() -> PathBuf {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+    match state_home {
+        Some(dir) => dir.join("audio-metadata").join("backups"),
+        None => std::env::temp_dir(),
+    }
+}
+
+/// Where backups are kept and how long for
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    /// Directory that runs are created in
+    pub root: PathBuf,
+    /// Back files up before changing them
+    pub enabled: bool,
+    /// Runs older than this are pruned
+    pub max_age: Option<Duration>,
+    /// The oldest runs are pruned until all runs together take at most this many bytes
+    pub max_size: Option<u64>,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        BackupPolicy { root: default_root(), enabled: true, max_age: None, max_size: None }
+    }
+}
+
+impl BackupPolicy {
+    /// Start a backup run for a command on `source_root`. Nothing is backed up
+    /// if backups are disabled.
+    pub fn start(&self, source_root: &Path, operation: &'static str) -> BackupRun {
+        let location = if self.enabled { Location::Root(self.root.clone()) } else { Location::Disabled };
+        BackupRun::with_location(location, source_root, operation)
+    }
+
+    /// Whether a retention limit is set
+    pub fn has_limits(&self) -> bool {
+        self.max_age.is_some() || self.max_size.is_some()
+    }
+
+    /// The runs that are past the retention limits, oldest first. The most
+    /// recent run is always kept.
+    pub fn expired_runs(&self) -> Result<Vec<RunInfo>> {
+        let mut runs = list_runs(&self.root)?;
+        let Some(newest) = runs.pop() else { return Ok(Vec::new()) };
+
+        let now = now();
+        let (mut expired, kept): (Vec<_>, Vec<_>) = runs.into_iter().partition(|run| {
+            self.max_age.is_some_and(|max_age| now.saturating_sub(run.time()) > max_age.as_secs())
+        });
+        if let Some(max_size) = self.max_size {
+            let mut total = kept.iter().map(|run| run.size).sum::<u64>() + newest.size;
+            for run in kept {
+                if total <= max_size {
+                    break;
+                }
+                total -= run.size;
+                expired.push(run);
+            }
+        }
+        expired.sort_by_key(|run| (run.time(), run.dir.clone()));
+        Ok(expired)
+    }
+
+    /// Delete the runs that are past the retention limits, returning them
+    pub fn prune(&self) -> Result<Vec<RunInfo>> {
+        let expired = self.expired_runs()?;
+        for run in &expired {
+            fs::remove_dir_all(&run.dir).map_err(Error::backup(&run.dir))?;
+        }
+        Ok(expired)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A file copied into a backup run, as recorded in the run's manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Absolute path of the file that was backed up
+    pub original: PathBuf,
+    /// Path of the copy, relative to the run directory
+    pub backup: PathBuf,
+    /// SHA-256 of the original contents, in hex
+    pub sha256: String,
+    /// The command that was about to change the file, e.g. "set" or "convert"
+    pub operation: String,
+    /// When the backup was taken, in seconds since the Unix epoch
+    pub time: u64,
+    /// Where the file was moved to, for renames. No copy is kept of a renamed
+    /// file, so `backup` is empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<PathBuf>,
+    /// Directories the rename had to create for `renamed_to`, deepest first,
+    /// which are removed again when the rename is undone and they are empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub created_dirs: Vec<PathBuf>,
+}
+
+/// Where a run's directory goes
+#[derive(Debug)]
+enum Location {
+    /// A new, uniquely named directory under this root
+    Root(PathBuf),
+    /// Exactly this directory, which may already exist
+    Dir(PathBuf),
+    /// Nothing is backed up
+    Disabled,
+}
+
+/// A run's directory and manifest, once the first file has been backed up
+#[derive(Debug)]
+struct RunState {
+    dir: PathBuf,
+    manifest: File,
+    /// Backup path (relative to `dir`) of each original, by canonical path
+    backups: HashMap<PathBuf, PathBuf>,
+}
+
+/// The backups taken by one run of a command. Files are laid out by their path
+/// relative to the file or directory the command was run on, and only the
+/// first copy of each file is kept, so the backup is always the pristine
+/// original. The run directory and its manifest are only created once the
+/// first file is backed up.
+#[derive(Debug)]
+pub struct BackupRun {
+    location: Location,
+    source_root: PathBuf,
+    operation: &'static str,
+    state: Mutex<Option<RunState>>,
+}
+
+impl BackupRun {
+    /// Back up into a new directory under `root`, named after the current time
+    /// and made unique if another run started in the same second
+    pub fn new(root: &Path, source_root: &Path, operation: &'static str) -> Self {
+        BackupRun::with_location(Location::Root(root.to_path_buf()), source_root, operation)
+    }
+
+    /// Back up into `dir`, which is created if needed
+    pub fn in_dir(dir: &Path, source_root: &Path, operation: &'static str) -> Self {
+        BackupRun::with_location(Location::Dir(dir.to_path_buf()), source_root, operation)
+    }
+
+    fn with_location(location: Location, source_root: &Path, operation: &'static str) -> Self {
+        let source_root = if source_root.is_dir() {
+            source_root
+        } else {
+            source_root.parent().unwrap_or(Path::new(""))
+        };
+        BackupRun { location, source_root: source_root.to_path_buf(), operation, state: Mutex::new(None) }
+    }
+
+    /// The run directory, or `None` if no file has been backed up yet
+    pub fn dir(&self) -> Option<PathBuf> {
+        self.state.lock().unwrap().as_ref().map(|state| state.dir.clone())
+    }
+
+    /// Copy a file into the run and record it in the manifest, returning the
+    /// path of the copy, or `None` if backups are disabled. A file that is
+    /// already in the run is not copied again.
+    pub fn back_up(&self, path: &Path) -> Result<Option<PathBuf>> {
+        if let Location::Disabled = self.location {
+            return Ok(None);
+        }
+        let original = fs::canonicalize(path).map_err(Error::backup(path))?;
+        let (dir, relative) = {
+            let mut state = self.state.lock().unwrap();
+            if state.is_none() {
+                *state = Some(self.create()?);
+            }
+            let state = state.as_mut().unwrap();
+            if let Some(relative) = state.backups.get(&original) {
+                return Ok(Some(state.dir.join(relative)));
+            }
+            // The path is reserved while the file is copied, so files copied at
+            // the same time cannot be given the same one
+            let relative = unique_relative_path(self.relative_path(path), &state.backups);
+            state.backups.insert(original.clone(), relative.clone());
+            (state.dir.clone(), relative)
+        };
+
+        let backup_path = dir.join(&relative);
+        let result = self.copy_and_record(path, &original, &backup_path, &relative);
+        if result.is_err() {
+            // A failed backup must not be reported as existing by later calls
+            if let Some(state) = self.state.lock().unwrap().as_mut() {
+                state.backups.remove(&original);
+            }
+            let _ = fs::remove_file(&backup_path);
+        }
+        result.map(|()| Some(backup_path))
+    }
+
+    /// Copy `path` to `backup_path` and add it to the manifest
+    fn copy_and_record(&self, path: &Path, original: &Path, backup_path: &Path, relative: &Path) -> Result<()> {
+        let parent = backup_path.parent().unwrap();
+        fs::create_dir_all(parent).map_err(Error::backup(parent))?;
+        fs::copy(path, backup_path).map_err(Error::backup(path))?;
+
+        self.append(&ManifestEntry {
+            original: original.to_path_buf(),
+            backup: relative.to_path_buf(),
+            sha256: sha256_file(backup_path).map_err(Error::backup(backup_path))?,
+            operation: self.operation.to_string(),
+            time: now(),
+            renamed_to: None,
+            created_dirs: Vec::new(),
+        })
+    }
+
+    /// Record that a file is about to be moved from `from` to `to`, so the
+    /// move can be undone. Nothing is copied. Must be called before the
+    /// directories for `to` are created, so the ones the move needs are
+    /// recorded too.
+    pub fn record_rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Location::Disabled = self.location {
+            return Ok(());
+        }
+        let to = std::path::absolute(to).map_err(Error::backup(to))?;
+        let created_dirs = to.ancestors().skip(1)
+            .take_while(|dir| !dir.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        self.append(&ManifestEntry {
+            original: fs::canonicalize(from).map_err(Error::backup(from))?,
+            backup: PathBuf::new(),
+            sha256: sha256_file(from).map_err(Error::backup(from))?,
+            operation: self.operation.to_string(),
+            time: now(),
+            renamed_to: Some(to),
+            created_dirs,
+        })
+    }
+
+    /// Append an entry to the manifest, creating the run first if needed
+    fn append(&self, entry: &ManifestEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| Error::Backup { path: entry.original.clone(), source: io::Error::other(e) })?;
+        line.push('\n');
+        let mut state = self.state.lock().unwrap();
+        if state.is_none() {
+            *state = Some(self.create()?);
+        }
+        let state = state.as_mut().unwrap();
+        let manifest_path = state.dir.join(MANIFEST_NAME);
+        state.manifest.write_all(line.as_bytes()).map_err(Error::backup(&manifest_path))
+    }
+
+    /// Path of a file relative to the source root, mirrored inside the run
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.source_root)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .unwrap_or(Path::new(path.file_name().unwrap()))
+            .to_path_buf()
+    }
+
+    /// Create the run directory and open its manifest
+    fn create(&self) -> Result<RunState> {
+        let dir = match &self.location {
+            Location::Dir(dir) => {
+                fs::create_dir_all(dir).map_err(Error::backup(dir))?;
+                dir.clone()
+            }
+            Location::Root(root) => create_unique_dir(root)?,
+            Location::Disabled => unreachable!("disabled runs never back up"),
+        };
+        let path = dir.join(MANIFEST_NAME);
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(Error::backup(&path))?;
+        Ok(RunState { dir, manifest, backups: HashMap::new() })
+    }
+}
+
+/// Create `<root>/audio-metadata-<secs>`, adding `-2`, `-3`, ... if that name is taken.
+/// `create_dir` fails on existing directories, so concurrent runs cannot share one.
+fn create_unique_dir(root: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(root).map_err(Error::backup(root))?;
+    let name = format!("{}{}", RUN_PREFIX, now());
+    for attempt in 1.. {
+        let dir = match attempt {
+            1 => root.join(&name),
+            _ => root.join(format!("{}-{}", name, attempt)),
+        };
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::Backup { path: dir, source: e }),
+        }
+    }
+    unreachable!()
+}
+
+/// Make a backup path unique within a run, for different originals that map
+/// to the same relative path (e.g. single files from different directories)
+fn unique_relative_path(relative: PathBuf, backups: &HashMap<PathBuf, PathBuf>) -> PathBuf {
+    let taken = |candidate: &Path| backups.values().any(|used| used == candidate);
+    if !taken(&relative) {
+        return relative;
+    }
+    let stem = relative.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = relative.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|n| relative.with_file_name(format!("{}~{}{}", stem, n, extension)))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// A backup run found on disk
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    pub dir: PathBuf,
+    pub entries: Vec<ManifestEntry>,
+    /// Total size of the run directory in bytes
+    pub size: u64,
+}
+
+impl RunInfo {
+    /// When the run started, in seconds since the Unix epoch
+    pub fn time(&self) -> u64 {
+        self.entries.first().map_or(0, |entry| entry.time)
+    }
+}
+
+/// List the backup runs under `root` that have a manifest, oldest first
+pub fn list_runs(root: &Path) -> Result<Vec<RunInfo>> {
+    let mut runs = Vec::new();
+    if !root.exists() {
+        return Ok(runs);
+    }
+    for entry in fs::read_dir(root).map_err(Error::io(root))? {
+        let dir = entry.map_err(Error::io(root))?.path();
+        let is_run = dir.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(RUN_PREFIX));
+        if is_run && dir.join(MANIFEST_NAME).is_file() {
+            runs.push(read_run(dir)?);
+        }
+    }
+    runs.sort_by_key(|run| (run.time(), run.dir.clone()));
+    Ok(runs)
+}
+
+/// Read the manifest and size of a backup run directory
+pub fn read_run(dir: PathBuf) -> Result<RunInfo> {
+    let entries = read_manifest(&dir)?;
+    let size = dir_size(&dir).map_err(Error::io(&dir))?;
+    Ok(RunInfo { dir, entries, size })
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// Parse an age such as `30d`, `12h` or `2w` (units: s, m, h, d, w)
+pub fn parse_age(text: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidInput(format!("Invalid age '{}': expected a number and a unit (s, m, h, d or w), e.g. 30d", text));
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let number: u64 = text[..split].parse().map_err(|_| invalid())?;
+    let unit = match &text[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return Err(invalid()),
+    };
+    let seconds = number.checked_mul(unit)
+        .ok_or_else(|| Error::InvalidInput(format!("Invalid age '{}': too long", text)))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parse a size in bytes, optionally with a binary unit: `500M`, `2G`, `1.5GiB`
+pub fn parse_size(text: &str) -> Result<u64> {
+    let invalid = || Error::InvalidInput(format!("Invalid size '{}': expected a number of bytes with an optional unit (K, M, G or T), e.g. 2G", text));
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let number: f64 = text[..split].parse().map_err(|_| invalid())?;
+    let unit = text[split..].trim_end_matches("iB").trim_end_matches('B');
+    let power = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(invalid()),
+    };
+    let bytes = number * 1024f64.powi(power);
+    // u64::MAX rounds up to 2^64 as a float, so this also rules out sizes that would saturate
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(Error::InvalidInput(format!("Invalid size '{}': too large", text)));
+    }
+    Ok(bytes as u64)
+}
+
+/// Read the manifest of a backup run directory
+pub fn read_manifest(dir: &Path) -> Result<Vec<ManifestEntry>> {
+    let path = dir.join(MANIFEST_NAME);
+    let file = File::open(&path).map_err(Error::io(&path))?;
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(Error::io(&path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| ParseError(format!("line {}: {}", number + 1, e)))
+            .map_err(ParseError::in_file(&path, "backup manifest"))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// What `restore_entry` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restored {
+    /// The original was overwritten with the backup
+    Restored,
+    /// The original already matched the backup
+    Unchanged,
+}
+
+/// Copy a backed-up file back to its original path, after checking that the
+/// backup still matches the checksum in the manifest, or move a renamed file
+/// back to where it was
+pub fn restore_entry(run_dir: &Path, entry: &ManifestEntry) -> Result<Restored> {
+    if let Some(renamed_to) = &entry.renamed_to {
+        return undo_rename(&entry.original, renamed_to, &entry.created_dirs);
+    }
+    let backup_path = run_dir.join(&entry.backup);
+    let checksum = sha256_file(&backup_path).map_err(Error::backup(&backup_path))?;
+    if checksum != entry.sha256 {
+        return Err(Error::Backup {
+            path: backup_path,
+            source: io::Error::new(io::ErrorKind::InvalidData, "checksum does not match the manifest"),
+        });
+    }
+
+    if sha256_file(&entry.original).is_ok_and(|current| current == checksum) {
+        return Ok(Restored::Unchanged);
+    }
+    if let Some(parent) = entry.original.parent() {
+        fs::create_dir_all(parent).map_err(Error::backup(parent))?;
+    }
+    atomic::copy(&backup_path, &entry.original)?;
+    Ok(Restored::Restored)
+}
+
+/// Move a renamed file back, removing the directories the rename created if
+/// nothing else is left in them
+fn undo_rename(original: &Path, renamed_to: &Path, created_dirs: &[PathBuf]) -> Result<Restored> {
+    if !renamed_to.exists() && original.exists() {
+        return Ok(Restored::Unchanged);
+    }
+    if original.exists() {
+        return Err(Error::Backup {
+            path: original.to_path_buf(),
+            source: io::Error::new(io::ErrorKind::AlreadyExists, "another file is in the way"),
+        });
+    }
+    if let Some(parent) = original.parent() {
+        fs::create_dir_all(parent).map_err(Error::backup(parent))?;
+    }
+    atomic::rename(renamed_to, original)?;
+    for dir in created_dirs {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(Restored::Restored)
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_back_up_and_restore() {
+        let root = std::env::temp_dir().join(format!("audio-metadata-backup-test-{}", std::process::id()));
+        let source = root.join("music");
+        fs::create_dir_all(source.join("Album")).unwrap();
+        let file = source.join("Album/01.mp3");
+        fs::write(&file, b"original").unwrap();
+
+        let run = BackupRun::new(&root.join("backups"), &source, "set");
+        assert_eq!(run.dir(), None);
+        let backup_path = run.back_up(&file).unwrap().unwrap();
+        let run_dir = run.dir().unwrap();
+        assert_eq!(backup_path, run_dir.join("Album/01.mp3"));
+        fs::write(&file, b"changed").unwrap();
+
+        // The first, pristine copy is kept if the file is backed up again
+        assert_eq!(run.back_up(&file).unwrap(), Some(backup_path.clone()));
+        assert_eq!(fs::read(&backup_path).unwrap(), b"original");
+
+        // A failed copy is not recorded, so trying again fails again
+        let unreadable = source.join("Album");
+        assert!(run.back_up(&unreadable).is_err());
+        assert!(run.back_up(&unreadable).is_err());
+
+        // A second run in the same second gets its own directory
+        let other = BackupRun::new(&root.join("backups"), &source, "set");
+        other.back_up(&file).unwrap();
+        assert_ne!(other.dir().unwrap(), run_dir);
+        fs::remove_dir_all(other.dir().unwrap()).unwrap();
+
+        let runs = list_runs(&root.join("backups")).unwrap();
+        assert_eq!(runs.len(), 1);
+        let entry = &runs[0].entries[0];
+        assert_eq!(entry.original, fs::canonicalize(&file).unwrap());
+        assert_eq!(entry.operation, "set");
+
+        assert_eq!(runs[0].entries.len(), 1);
+        assert_eq!(restore_entry(&run_dir, entry).unwrap(), Restored::Restored);
+        assert_eq!(fs::read(&file).unwrap(), b"original");
+        assert_eq!(restore_entry(&run_dir, entry).unwrap(), Restored::Unchanged);
+
+        // A tampered backup is refused
+        fs::write(&backup_path, b"tampered").unwrap();
+        assert!(matches!(restore_entry(&run_dir, entry), Err(Error::Backup { .. })));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_undo_rename() {
+        let root = std::env::temp_dir().join(format!("audio-metadata-undo-rename-test-{}", std::process::id()));
+        let source = root.join("music");
+        let output = root.join("sorted");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        let file = source.join("01.mp3");
+        fs::write(&file, b"original").unwrap();
+
+        let target = output.join("Artist/Album/01.mp3");
+        let run = BackupRun::new(&root.join("backups"), &source, "rename");
+        run.record_rename(&file, &target).unwrap();
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::rename(&file, &target).unwrap();
+
+        let runs = list_runs(&root.join("backups")).unwrap();
+        let entry = &runs[0].entries[0];
+        assert_eq!(entry.created_dirs, [output.join("Artist/Album"), output.join("Artist")]);
+        assert_eq!(restore_entry(&run.dir().unwrap(), entry).unwrap(), Restored::Restored);
+        assert_eq!(fs::read(&file).unwrap(), b"original");
+        // Only the directories the rename created are removed, not the empty output directory
+        assert!(!output.join("Artist").exists());
+        assert!(output.is_dir());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_retention() {
+        let root = std::env::temp_dir().join(format!("audio-metadata-retention-test-{}", std::process::id()));
+        // Three runs of 100 bytes each: 40 days old, 10 days old and current
+        for (name, age_days) in [("old", 40), ("recent", 10), ("current", 0)] {
+            let dir = root.join(format!("{}{}", RUN_PREFIX, name));
+            fs::create_dir_all(&dir).unwrap();
+            let entry = ManifestEntry {
+                original: PathBuf::from("/music/01.mp3"),
+                backup: PathBuf::from("01.mp3"),
+                sha256: String::new(),
+                operation: "set".to_string(),
+                time: now() - age_days * 86_400,
+                renamed_to: None,
+                created_dirs: Vec::new(),
+            };
+            fs::write(dir.join(MANIFEST_NAME), "").unwrap();
+            let line = serde_json::to_string(&entry).unwrap();
+            fs::write(dir.join("01.mp3"), vec![0; 100 - line.len() - 1]).unwrap();
+            fs::write(dir.join(MANIFEST_NAME), line + "\n").unwrap();
+        }
+        let expired = |policy: BackupPolicy| -> Vec<String> {
+            policy.expired_runs().unwrap().iter()
+                .map(|run| run.dir.file_name().unwrap().to_string_lossy().replace(RUN_PREFIX, ""))
+                .collect()
+        };
+        let policy = BackupPolicy { root: root.clone(), ..BackupPolicy::default() };
+
+        assert_eq!(expired(BackupPolicy { max_age: Some(parse_age("30d").unwrap()), ..policy.clone() }), ["old"]);
+        assert_eq!(expired(BackupPolicy { max_size: Some(parse_size("200").unwrap()), ..policy.clone() }), ["old"]);
+        // The most recent run is kept even if it alone is over the limit
+        assert_eq!(expired(BackupPolicy { max_age: Some(parse_age("1s").unwrap()), max_size: Some(0), ..policy.clone() }), ["old", "recent"]);
+        assert!(expired(policy.clone()).is_empty());
+
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1.5KiB").unwrap(), 1536);
+        assert!(parse_age("30").is_err());
+        assert!(matches!(parse_age("100000000000000w"), Err(Error::InvalidInput(_))));
+        assert!(matches!(parse_size("99999999999T"), Err(Error::InvalidInput(_))));
+        assert!(matches!(parse_size(&"9".repeat(400)), Err(Error::InvalidInput(_))));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
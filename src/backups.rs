@@ -0,0 +1,57 @@
+use anyhow::{Result, bail};
+
+use audio_metadata::{BackupPolicy, RunInfo};
+
+use crate::restore::format_time;
+
+/// Delete (or with `dry_run`, list) the backup runs past the policy's retention limits
+pub fn prune(policy: &BackupPolicy, dry_run: bool) -> Result<()> {
+    if !policy.has_limits() {
+        bail!("No retention limit given: use --backup-max-age or --backup-max-size");
+    }
+
+    let runs = if dry_run { policy.expired_runs()? } else { policy.prune()? };
+    if runs.is_empty() {
+        println!("No backup runs in {} are past the retention limits", policy.root.display());
+    }
+    for run in &runs {
+        println!("{} {}", if dry_run { "Would remove" } else { "Removed" }, describe(run));
+    }
+    Ok(())
+}
+
+/// Prune old backup runs after a command, if backups are on and a limit is set
+pub fn apply_retention(policy: &BackupPolicy) -> Result<()> {
+    if !policy.enabled || !policy.has_limits() {
+        return Ok(());
+    }
+    for run in policy.prune()? {
+        println!("Removed old backup {}", describe(&run));
+    }
+    Ok(())
+}
+
+fn describe(run: &RunInfo) -> String {
+    format!(
+        "{} ({}, {})",
+        run.dir.file_name().unwrap().to_string_lossy(),
+        format_time(run.time()),
+        format_size(run.size)
+    )
+}
+
+/// Format a number of bytes with a binary unit, e.g. "1.5 MiB"
+fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
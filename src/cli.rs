@@ -1,10 +1,41 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use audio_metadata::backup::{parse_age, parse_size};
+use audio_metadata::picture::parse_picture_type;
+use audio_metadata::tag::{TagItem, parse_date, parse_isrc, parse_tag_assignment, parse_tag_item, parse_tag_key};
+use audio_metadata::{Mp3Preset, SymlinkPolicy, Target, WalkOptions};
+
+use crate::config::WalkSettings;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Read defaults from this config file only, instead of the user and project config files
+    #[arg(long, global = true, env = "AUDIO_METADATA_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Apply the defaults of this profile from the config files
+    #[arg(long, global = true, env = "AUDIO_METADATA_PROFILE", value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Directory to keep backups in (default: ~/.local/state/audio-metadata/backups)
+    #[arg(long, global = true, env = "AUDIO_METADATA_BACKUP_DIR", value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Delete backup runs older than this after each run, e.g. 30d, 12h or 2w
+    #[arg(long, global = true, env = "AUDIO_METADATA_BACKUP_MAX_AGE", value_name = "AGE", value_parser = parse_age)]
+    pub backup_max_age: Option<Duration>,
+
+    /// Delete the oldest backup runs after each run until all of them fit in this size, e.g. 2G
+    #[arg(long, global = true, env = "AUDIO_METADATA_BACKUP_MAX_SIZE", value_name = "SIZE", value_parser = parse_size)]
+    pub backup_max_size: Option<u64>,
 }
 
 #[derive(Subcommand)]
@@ -15,7 +46,9 @@ pub enum Commands {
         #[arg(short, long)]
         file: String,
 
-        /// Path to cover art image
+        /// Path to cover art image, or "auto" to use the best image in each directory
+        /// (cover.jpg, folder.png, front.jpg, ...) for files without pictures.
+        /// Pass an image file named "auto" as "./auto"
         #[arg(short, long)]
         cover: Option<String>,
 
@@ -23,30 +56,72 @@ pub enum Commands {
         #[arg(short, long)]
         album: Option<String>,
 
-        /// Artist name to set
+        /// Artist name to set; repeat for several artists, e.g. -r "A" -r "B"
         #[arg(short = 'r', long)]
-        artist: Option<String>,
+        artist: Vec<String>,
 
         /// Song title to set
         #[arg(short = 't', long)]
         title: Option<String>,
 
+        #[command(flatten)]
+        fields: Box<FieldArgs>,
+
         /// Track number to set
         #[arg(short = 'n', long)]
         track: Option<u32>,
 
+        /// Total number of tracks to set (written as "n/N" in MP3 files)
+        #[arg(long)]
+        track_total: Option<u32>,
+
+        /// Disc number to set
+        #[arg(long)]
+        disc: Option<u32>,
+
+        /// Total number of discs to set (written as "n/N" in MP3 files)
+        #[arg(long)]
+        disc_total: Option<u32>,
+
+        /// Joins several values of a field in ID3v2.3 tags, which cannot store them separately [default: /]
+        #[arg(long, value_name = "SEPARATOR")]
+        id3v23_separator: Option<String>,
+
         /// Infer track name from filename (removes track numbers and file extension)
         #[arg(long)]
         infer_track: bool,
 
-        /// Infer track numbers based on sorted order of files in directory
+        /// Read fields from the file and parent directory names with a template, e.g. "%n - %a - %t".
+        /// Placeholders: %a artist, %b album, %t title, %n track, %d disc, %y year, %i ignored;
+        /// "/" matches a directory separator
+        #[arg(long, value_name = "PATTERN")]
+        from_filename: Option<String>,
+
+        /// Infer track numbers based on sorted order of files in directory, and the track total
+        /// from their count (numbering restarts in each directory with --recursive).
+        /// Directories named like "CD1" or "Disc 2" also set the disc number and disc total
         #[arg(long)]
         infer_order: bool,
+
+        /// Show the changes that would be made to each file without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Change files without backing them up first
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        /// Number of files to process at once (default: number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<NonZeroUsize>,
     },
 
-    /// Convert FLAC files to MP3
+    /// Convert FLAC, WAV, AIFF or ALAC files to MP3, Opus, Ogg Vorbis, AAC or ALAC
     Convert {
-        /// Path to the FLAC file or directory
+        /// Path to the audio file or directory
         #[arg(short, long)]
         file: String,
 
@@ -54,8 +129,375 @@ pub enum Commands {
         #[arg(short, long)]
         output: Option<String>,
 
-        /// MP3 bitrate in kbps (default: 320)
-        #[arg(short, long, default_value = "320")]
-        bitrate: u32,
+        /// Output format (default: mp3)
+        #[arg(long, value_enum)]
+        to: Option<Format>,
+
+        /// Bitrate in kbps (default: 320 for MP3, 160 for Opus, 256 for AAC)
+        #[arg(short, long)]
+        bitrate: Option<u32>,
+
+        /// VBR quality level: 0-9 for MP3 (V0 is best), 0-10 for Vorbis (default: 6)
+        #[arg(short, long)]
+        quality: Option<u8>,
+
+        /// Encode MP3 at an average bitrate (set with --bitrate) instead of a constant one
+        #[arg(long, conflicts_with = "quality")]
+        abr: bool,
+
+        /// MP3 encoding preset
+        #[arg(long, value_enum, conflicts_with_all = ["bitrate", "quality", "abr"])]
+        preset: Option<Preset>,
+
+        /// Show the output path, encoder settings and tags of each conversion without converting
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Convert files without backing up the inputs first
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+
+        /// Number of files to process at once (default: number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<NonZeroUsize>,
+    },
+    /// Show the existing tags of an audio file or directory
+    Show {
+        /// Path to the audio file or directory
+        #[arg(short, long)]
+        file: String,
+
+        /// Print the tags as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Rename audio files after their tags
+    Rename {
+        /// Path to the audio file or directory
+        #[arg(short, long)]
+        file: String,
+
+        /// Name template, e.g. "{track:02} - {title}" or "{artist}/{album} ({year})/{track:02} - {title}".
+        /// Placeholders: {artist}, {albumartist}, {album}, {title}, {track}, {disc}, {year}, {date}, {genre}
+        #[arg(short, long)]
+        template: Option<String>,
+
+        /// Directory to move files into when the template has folders
+        /// (defaults to the directory being renamed)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Show the new names without renaming anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Rename files without recording the moves for restore
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+    },
+
+    /// Extract, replace or remove embedded pictures such as cover art
+    Cover {
+        #[command(subcommand)]
+        command: CoverCommand,
+    },
+
+    /// List backup runs, or restore the files backed up by one
+    Restore {
+        /// Backup run to restore: its directory name or path, or "latest".
+        /// The runs are listed if omitted.
+        run: Option<String>,
+
+        /// Only restore this original file (repeatable)
+        #[arg(short, long, requires = "run")]
+        file: Vec<String>,
+    },
+
+    /// Manage backup runs
+    Backups {
+        #[command(subcommand)]
+        command: BackupsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BackupsCommand {
+    /// Delete the backup runs that are past --backup-max-age or --backup-max-size
+    Prune {
+        /// List the runs that would be deleted without deleting them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CoverCommand {
+    /// Save embedded pictures as image files, named like "01 - Song - front cover.jpg"
+    Extract {
+        /// Path to the audio file or directory
+        #[arg(short, long)]
+        file: String,
+
+        /// Directory to save the pictures in (defaults to the directory of each audio file)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Only extract pictures of this type, e.g. front, back, booklet or artist (repeatable)
+        #[arg(long = "type", value_name = "TYPE", value_parser = parse_picture_type)]
+        picture_type: Vec<u32>,
+
+        /// List the pictures that would be saved without saving them
+        #[arg(long)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+    },
+
+    /// Embed an image, replacing any picture of the same type
+    Set {
+        /// Path to the audio file or directory
+        #[arg(short, long)]
+        file: String,
+
+        /// Path to the image
+        #[arg(short, long)]
+        image: String,
+
+        /// Picture type, e.g. front, back, booklet, artist or a number from 0 to 20
+        #[arg(long = "type", value_name = "TYPE", value_parser = parse_picture_type, default_value = "front")]
+        picture_type: u32,
+
+        /// Description of the picture
+        #[arg(long, default_value = "")]
+        description: String,
+
+        /// MIME type of the image (detected for JPEG, PNG and GIF images)
+        #[arg(long)]
+        mime: Option<String>,
+
+        /// Show the changes that would be made to each file without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Change files without backing them up first
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
     },
-} 
\ No newline at end of file
+
+    /// Remove embedded pictures
+    Remove {
+        /// Path to the audio file or directory
+        #[arg(short, long)]
+        file: String,
+
+        /// Only remove pictures of this type (repeatable; all pictures are removed if omitted)
+        #[arg(long = "type", value_name = "TYPE", value_parser = parse_picture_type)]
+        picture_type: Vec<u32>,
+
+        /// Show the changes that would be made to each file without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Change files without backing them up first
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        walk: WalkArgs,
+    },
+}
+
+impl CoverCommand {
+    /// Path to the audio file or directory
+    pub fn file(&self) -> &str {
+        match self {
+            CoverCommand::Extract { file, .. } | CoverCommand::Set { file, .. } | CoverCommand::Remove { file, .. } => file,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// MP3 (LAME)
+    Mp3,
+    /// Opus in an Ogg container (.opus)
+    Opus,
+    /// Ogg Vorbis (.ogg)
+    Vorbis,
+    /// AAC in an M4A container
+    Aac,
+    /// Apple Lossless in an M4A container
+    Alac,
+}
+
+impl From<Format> for Target {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Mp3 => Target::Mp3,
+            Format::Opus => Target::Opus,
+            Format::Vorbis => Target::Vorbis,
+            Format::Aac => Target::Aac,
+            Format::Alac => Target::Alac,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// VBR V5, around 130 kbps
+    Portable,
+    /// VBR V2, around 190 kbps
+    Standard,
+    /// VBR V0, around 245 kbps
+    ArchiveTransparent,
+    /// CBR 320 kbps
+    Insane,
+}
+
+impl From<Preset> for Mp3Preset {
+    fn from(preset: Preset) -> Self {
+        match preset {
+            Preset::Portable => Mp3Preset::Portable,
+            Preset::Standard => Mp3Preset::Standard,
+            Preset::ArchiveTransparent => Mp3Preset::ArchiveTransparent,
+            Preset::Insane => Mp3Preset::Insane,
+        }
+    }
+}
+
+/// The extra fields `set` can write, besides artist, album, title and numbers
+#[derive(Args)]
+pub struct FieldArgs {
+    /// Album artist to set (repeatable)
+    #[arg(long)]
+    pub album_artist: Vec<String>,
+
+    /// Release date to set: YYYY, YYYY-MM or YYYY-MM-DD (ID3v2.3 tags keep the year, day and month)
+    #[arg(long, value_parser = parse_date)]
+    pub date: Option<String>,
+
+    /// Genre to set (repeatable)
+    #[arg(long)]
+    pub genre: Vec<String>,
+
+    /// Composer to set (repeatable)
+    #[arg(long)]
+    pub composer: Vec<String>,
+
+    /// Performer to set (repeatable)
+    #[arg(long)]
+    pub performer: Vec<String>,
+
+    /// Comment to set
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Tempo in beats per minute to set
+    #[arg(long)]
+    pub bpm: Option<u32>,
+
+    /// International Standard Recording Code to set, e.g. USS1Z9900001
+    #[arg(long, value_parser = parse_isrc)]
+    pub isrc: Option<String>,
+
+    /// Copyright message to set
+    #[arg(long)]
+    pub copyright: Option<String>,
+
+    /// Unsynchronised lyrics to set
+    #[arg(long, conflicts_with = "lyrics_file")]
+    pub lyrics: Option<String>,
+
+    /// Read the unsynchronised lyrics to set from a text file
+    #[arg(long, value_name = "FILE")]
+    pub lyrics_file: Option<PathBuf>,
+
+    /// Set any tag, e.g. CATALOGNUMBER=ABC-123 (repeatable; repeat a key for several values).
+    /// Standard field names such as GENRE set that field; other keys become TXXX frames in MP3 files
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_tag_assignment)]
+    pub tag: Vec<(String, String)>,
+
+    /// Remove a tag by key, e.g. RELEASETYPE (repeatable)
+    #[arg(long, value_name = "KEY", value_parser = parse_tag_key)]
+    pub remove_tag: Vec<String>,
+
+    /// Remove fields, e.g. "comment,lyrics" (repeatable). Takes field names such as album-artist,
+    /// custom tag keys, or "pictures" for the embedded pictures
+    #[arg(long, value_name = "FIELD", value_delimiter = ',', value_parser = parse_tag_item)]
+    pub clear: Vec<TagItem>,
+
+    /// Remove all tags, pictures and ID3v1 trailers before setting any fields given with other options
+    #[arg(long, conflicts_with = "keep")]
+    pub strip_all: bool,
+
+    /// Remove everything except these fields, e.g. "artist,album,title,pictures", including
+    /// unknown frames and ID3v1 trailers
+    #[arg(long, value_name = "FIELD,...", value_delimiter = ',', value_parser = parse_tag_item)]
+    pub keep: Option<Vec<TagItem>>,
+}
+
+/// Options controlling which files in a directory are processed
+#[derive(Args)]
+pub struct WalkArgs {
+    /// Also process files in subdirectories
+    #[arg(short = 'R', long)]
+    recursive: bool,
+
+    /// Maximum subdirectory depth to descend into (1 = immediate subdirectories)
+    #[arg(long, requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// How to treat symbolic links (default: files)
+    #[arg(long, value_enum)]
+    symlinks: Option<Symlinks>,
+
+    /// Only process files whose path relative to the directory matches this glob (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Skip files and directories whose relative path matches this glob (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    /// Ignore symbolic links
+    Skip,
+    /// Process links to files but do not descend into linked directories
+    Files,
+    /// Process links to files and descend into linked directories
+    Follow,
+}
+
+impl WalkArgs {
+    /// The walk options, falling back to the config defaults for options not given
+    pub fn with_defaults(self, defaults: WalkSettings) -> WalkOptions {
+        let symlinks = self.symlinks.or(defaults.symlinks).unwrap_or(Symlinks::Files);
+        WalkOptions {
+            recursive: self.recursive || defaults.recursive.unwrap_or(false),
+            max_depth: self.max_depth.or(defaults.max_depth),
+            symlinks: match symlinks {
+                Symlinks::Skip => SymlinkPolicy::Skip,
+                Symlinks::Files => SymlinkPolicy::Files,
+                Symlinks::Follow => SymlinkPolicy::Follow,
+            },
+            include: if self.include.is_empty() { defaults.include.unwrap_or_default() } else { self.include },
+            exclude: if self.exclude.is_empty() { defaults.exclude.unwrap_or_default() } else { self.exclude },
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use audio_metadata::{BackupPolicy, Error, Result};
+use audio_metadata::backup::{parse_age, parse_size};
+
+use crate::cli::{Format, Preset, Symlinks};
+
+/// Name of the per-library config file, looked for in the target directory and its parents
+pub const PROJECT_FILE: &str = ".audio-metadata.toml";
+
+/// Defaults for command-line options, read from the config files. Every
+/// field is optional; options given on the command line take precedence.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub jobs: Option<NonZeroUsize>,
+    pub walk: WalkSettings,
+    pub set: SetSettings,
+    pub convert: ConvertSettings,
+    pub rename: RenameSettings,
+    pub backup: BackupSettings,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WalkSettings {
+    pub recursive: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub symlinks: Option<Symlinks>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SetSettings {
+    pub artist: Option<Values>,
+    pub album: Option<String>,
+    pub album_artist: Option<Values>,
+    pub genre: Option<Values>,
+    pub composer: Option<Values>,
+    pub copyright: Option<String>,
+    pub id3v23_separator: Option<String>,
+    pub cover: Option<PathBuf>,
+    pub infer_track: Option<bool>,
+    pub from_filename: Option<String>,
+    pub infer_order: Option<bool>,
+}
+
+/// A setting for a multi-valued field: one value, e.g. `artist = "A"`, or a
+/// list, e.g. `artist = ["A", "B"]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Values {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Values> for Vec<String> {
+    fn from(values: Values) -> Self {
+        match values {
+            Values::One(value) => vec![value],
+            Values::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConvertSettings {
+    pub to: Option<Format>,
+    pub bitrate: Option<u32>,
+    pub quality: Option<u8>,
+    pub abr: Option<bool>,
+    pub preset: Option<Preset>,
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RenameSettings {
+    pub template: Option<String>,
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BackupSettings {
+    pub enabled: Option<bool>,
+    pub dir: Option<PathBuf>,
+    pub max_age: Option<String>,
+    pub max_size: Option<String>,
+}
+
+/// Copy every field that is set in `$other` over the one in `$self`
+macro_rules! merge_fields {
+    ($self:expr, $other:ident, $($field:ident),+) => {
+        $(if $other.$field.is_some() { $self.$field = $other.$field; })+
+    };
+}
+
+impl Settings {
+    /// Override these settings with every field that is set in `other`
+    fn merge(&mut self, other: Settings) {
+        merge_fields!(self, other, jobs);
+        let (walk, set, convert, rename, backup) = (other.walk, other.set, other.convert, other.rename, other.backup);
+        merge_fields!(self.walk, walk, recursive, max_depth, symlinks, include, exclude);
+        merge_fields!(
+            self.set, set,
+            artist, album, album_artist, genre, composer, copyright, id3v23_separator, cover, infer_track, from_filename, infer_order
+        );
+        merge_fields!(self.convert, convert, to, bitrate, quality, abr, preset, output);
+        merge_fields!(self.rename, rename, template, output);
+        merge_fields!(self.backup, backup, enabled, dir, max_age, max_size);
+    }
+
+    /// Make relative paths relative to the directory of the file they came from
+    fn resolve_paths(&mut self, base: &Path) {
+        // A cover of "auto" asks for the cover to be found in each directory rather than naming a file
+        let cover = self.set.cover.as_mut().filter(|cover| cover.as_os_str() != "auto");
+        let paths = [cover, self.convert.output.as_mut(), self.rename.output.as_mut(), self.backup.dir.as_mut()];
+        for path in paths.into_iter().flatten() {
+            *path = resolve_path(path, base);
+        }
+    }
+
+    /// The backup policy these settings describe
+    pub fn backup_policy(&self) -> Result<BackupPolicy> {
+        let mut policy = BackupPolicy {
+            enabled: self.backup.enabled.unwrap_or(true),
+            max_age: self.backup.max_age.as_deref().map(parse_age).transpose()?,
+            max_size: self.backup.max_size.as_deref().map(parse_size).transpose()?,
+            ..BackupPolicy::default()
+        };
+        if let Some(dir) = &self.backup.dir {
+            policy.root = dir.clone();
+        }
+        Ok(policy)
+    }
+}
+
+/// A config file: default settings at the top level plus named profiles
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+    /// Top-level keys that are not settings, which `deny_unknown_fields`
+    /// cannot catch through `flatten`
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// The user config file: `$XDG_CONFIG_HOME/audio-metadata/config.toml`,
+/// or `~/.config/audio-metadata/config.toml`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("audio-metadata").join("config.toml"))
+}
+
+/// Find the nearest project config file in `start` (or its directory, for a file) or its parents
+pub fn find_project_config(start: &Path) -> Option<PathBuf> {
+    let start = std::path::absolute(start).ok()?;
+    let dir = if start.is_dir() { start.as_path() } else { start.parent()? };
+    dir.ancestors().map(|dir| dir.join(PROJECT_FILE)).find(|path| path.is_file())
+}
+
+/// Load the settings for a command from the given config files, later files
+/// taking precedence, and apply `profile` from any of them on top
+pub fn load(files: &[PathBuf], profile: Option<&str>) -> Result<Settings> {
+    let mut settings = Settings::default();
+    let mut profiles: BTreeMap<String, Settings> = BTreeMap::new();
+    for path in files {
+        let file = read_file(path)?;
+        settings.merge(file.settings);
+        for (name, profile_settings) in file.profiles {
+            profiles.entry(name).or_default().merge(profile_settings);
+        }
+    }
+
+    if let Some(name) = profile {
+        let Some(profile_settings) = profiles.remove(name) else {
+            let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            let message = if known.is_empty() {
+                format!("Unknown profile '{}': no profiles are defined", name)
+            } else {
+                format!("Unknown profile '{}' (available: {})", name, known.join(", "))
+            };
+            return Err(Error::InvalidInput(message));
+        };
+        settings.merge(profile_settings);
+    }
+    Ok(settings)
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Io { path: path.to_path_buf(), source })?;
+    let mut file: ConfigFile = toml::from_str(&text).map_err(|e| Error::Parse {
+        path: path.to_path_buf(),
+        format: "config",
+        message: e.message().to_string(),
+    })?;
+    if let Some(key) = file.unknown.keys().next() {
+        return Err(Error::Parse { path: path.to_path_buf(), format: "config", message: format!("unknown setting `{}`", key) });
+    }
+    let base = path.parent().unwrap_or(Path::new(""));
+    file.settings.resolve_paths(base);
+    for settings in file.profiles.values_mut() {
+        settings.resolve_paths(base);
+    }
+    Ok(file)
+}
+
+/// Expand a leading `~/` and make relative paths relative to `base`
+fn resolve_path(path: &Path, base: &Path) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix("~")
+        && let Some(home) = std::env::var_os("HOME")
+    {
+        return Path::new(&home).join(rest);
+    }
+    base.join(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_merges_files_and_profiles() {
+        let dir = std::env::temp_dir().join(format!("audio-metadata-config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let user = dir.join("config.toml");
+        fs::write(&user, r#"
+jobs = 2
+
+[convert]
+to = "opus"
+bitrate = 128
+
+[profiles.phone.convert]
+bitrate = 96
+output = "phone"
+"#).unwrap();
+        let project = dir.join(PROJECT_FILE);
+        fs::write(&project, r#"
+[set]
+artist = ["Project Artist", "Guest"]
+
+[convert]
+to = "aac"
+
+[profiles.phone.walk]
+recursive = true
+"#).unwrap();
+
+        let files = [user.clone(), project];
+        let settings = load(&files, None).unwrap();
+        assert_eq!(settings.jobs, NonZeroUsize::new(2));
+        assert!(matches!(settings.convert.to, Some(Format::Aac)));
+        assert_eq!(settings.convert.bitrate, Some(128));
+        let artists: Vec<String> = settings.set.artist.unwrap().into();
+        assert_eq!(artists, ["Project Artist", "Guest"]);
+
+        let phone = load(&files, Some("phone")).unwrap();
+        assert_eq!(phone.convert.bitrate, Some(96));
+        assert_eq!(phone.convert.output, Some(dir.join("phone")));
+        assert_eq!(phone.walk.recursive, Some(true));
+
+        assert!(matches!(load(&files[..1], Some("car")), Err(Error::InvalidInput(_))));
+        fs::write(&user, "[convert]\nbitrat = 1\n").unwrap();
+        assert!(matches!(load(&files[..1], None), Err(Error::Parse { format: "config", .. })));
+        fs::write(&user, "job = 1\n").unwrap();
+        assert!(matches!(load(&files[..1], None), Err(Error::Parse { format: "config", .. })));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
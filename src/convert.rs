@@ -0,0 +1,542 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::atomic::TempFile;
+use crate::backup::BackupRun;
+use crate::error::{Error, Result};
+use crate::flac::picture_to_bytes;
+use crate::format::{WriteOptions, format_for_path};
+use crate::id3::{self, Frame};
+use crate::ogg;
+use crate::picture::Picture;
+use crate::progress::Progress;
+use crate::tag::{Field, Tag};
+use crate::tool;
+
+/// Extensions of the files that can be converted. `.m4a` inputs must hold ALAC.
+pub const INPUT_EXTENSIONS: [&str; 5] = ["flac", "wav", "aiff", "aif", "m4a"];
+
+/// Output format of a conversion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    #[default]
+    Mp3,
+    /// Opus in an Ogg container
+    Opus,
+    /// Ogg Vorbis
+    Vorbis,
+    /// AAC in an M4A (MP4) container
+    Aac,
+    /// Apple Lossless in an M4A (MP4) container
+    Alac,
+}
+
+impl Target {
+    /// Extension of the converted file
+    pub fn extension(self) -> &'static str {
+        match self {
+            Target::Mp3 => "mp3",
+            Target::Opus => "opus",
+            Target::Vorbis => "ogg",
+            Target::Aac | Target::Alac => "m4a",
+        }
+    }
+
+    /// ffmpeg encoder producing this format
+    fn encoder(self) -> &'static str {
+        match self {
+            Target::Mp3 => "libmp3lame",
+            Target::Opus => "libopus",
+            Target::Vorbis => "libvorbis",
+            Target::Aac => "aac",
+            Target::Alac => "alac",
+        }
+    }
+
+    /// Whether the container can carry embedded cover art through ffmpeg.
+    /// Ogg files get the cover as a METADATA_BLOCK_PICTURE comment instead.
+    fn keeps_cover_art(self) -> bool {
+        matches!(self, Target::Mp3 | Target::Aac | Target::Alac)
+    }
+
+    /// ffmpeg's name for the MP4 atom a field is stored in. Only M4A outputs
+    /// are tagged through ffmpeg: MP3 tags and Ogg comments are written natively.
+    fn metadata_key(self, field: Field) -> Option<&'static str> {
+        match self {
+            Target::Aac | Target::Alac => match field {
+                Field::Artist => Some("artist"),
+                Field::AlbumArtist => Some("album_artist"),
+                Field::Album => Some("album"),
+                Field::Title => Some("title"),
+                // MP4 stores the totals in the same atoms as the numbers
+                Field::TrackNumber => Some("track"),
+                Field::DiscNumber => Some("disc"),
+                Field::Date => Some("date"),
+                Field::Genre => Some("genre"),
+                Field::Composer => Some("composer"),
+                Field::Comment => Some("comment"),
+                Field::Bpm => Some("tmpo"),
+                Field::Copyright => Some("copyright"),
+                Field::Lyrics => Some("lyrics"),
+                _ => None,
+            },
+            Target::Mp3 | Target::Opus | Target::Vorbis => None,
+        }
+    }
+}
+
+/// Named MP3 encoding presets, after LAME's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mp3Preset {
+    /// VBR V5, around 130 kbps, for players with little storage
+    Portable,
+    /// VBR V2, around 190 kbps, transparent for most listeners
+    Standard,
+    /// VBR V0, around 245 kbps, for keeping as the only copy
+    ArchiveTransparent,
+    /// CBR 320 kbps, the highest MP3 bitrate
+    Insane,
+}
+
+impl Mp3Preset {
+    /// Name as accepted on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Mp3Preset::Portable => "portable",
+            Mp3Preset::Standard => "standard",
+            Mp3Preset::ArchiveTransparent => "archive-transparent",
+            Mp3Preset::Insane => "insane",
+        }
+    }
+
+    fn mode(self) -> Mp3Mode {
+        match self {
+            Mp3Preset::Portable => Mp3Mode::Vbr(5),
+            Mp3Preset::Standard => Mp3Mode::Vbr(2),
+            Mp3Preset::ArchiveTransparent => Mp3Mode::Vbr(0),
+            Mp3Preset::Insane => Mp3Mode::Cbr(320),
+        }
+    }
+}
+
+/// How LAME spends bits on an MP3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mp3Mode {
+    /// Constant bitrate in kbps
+    Cbr(u32),
+    /// Average bitrate in kbps
+    Abr(u32),
+    /// Variable bitrate at quality V0 (best) to V9
+    Vbr(u8),
+}
+
+impl Mp3Mode {
+    fn args(self) -> Vec<String> {
+        match self {
+            Mp3Mode::Cbr(bitrate) => vec!["-b:a".to_string(), format!("{}k", bitrate)],
+            Mp3Mode::Abr(bitrate) => vec!["-abr".to_string(), "1".to_string(), "-b:a".to_string(), format!("{}k", bitrate)],
+            Mp3Mode::Vbr(quality) => vec!["-q:a".to_string(), quality.to_string()],
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Mp3Mode::Cbr(bitrate) => format!("CBR {} kbps", bitrate),
+            Mp3Mode::Abr(bitrate) => format!("ABR {} kbps", bitrate),
+            Mp3Mode::Vbr(quality) => format!("VBR V{}", quality),
+        }
+    }
+}
+
+/// Settings for `convert_file`
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    pub target: Target,
+    /// Bitrate in kbps for MP3 (CBR, or ABR with `abr`), Opus, AAC and Vorbis
+    /// (nominal). Each lossy format has its own default.
+    pub bitrate: Option<u32>,
+    /// VBR quality level: V0 (best) to V9 for MP3, 0-10 (best) for Vorbis.
+    /// Takes precedence over `bitrate`.
+    pub quality: Option<u8>,
+    /// Encode MP3 at an average rather than constant bitrate
+    pub abr: bool,
+    /// MP3 preset, used instead of `bitrate`, `quality` and `abr`
+    pub preset: Option<Mp3Preset>,
+    /// Print what would be converted instead of converting
+    pub dry_run: bool,
+}
+
+impl ConvertOptions {
+    /// Encoder arguments for ffmpeg, after checking the settings suit the target
+    fn encoder_args(&self) -> Result<Vec<String>> {
+        let mut args = vec!["-codec:a".to_string(), self.target.encoder().to_string()];
+        if self.target != Target::Mp3 && (self.abr || self.preset.is_some()) {
+            return Err(Error::InvalidInput("ABR and presets are only available for MP3".to_string()));
+        }
+
+        match (self.target, self.quality, self.bitrate) {
+            (Target::Mp3, ..) => args.extend(self.mp3_mode()?.args()),
+            (Target::Alac, None, None) => {}
+            (Target::Alac, ..) => {
+                return Err(Error::InvalidInput("ALAC is lossless and takes no bitrate or quality setting".to_string()));
+            }
+            (Target::Vorbis, Some(quality), _) if quality <= 10 => {
+                args.extend(["-q:a".to_string(), quality.to_string()]);
+            }
+            (Target::Vorbis, Some(quality), _) => {
+                return Err(Error::InvalidInput(format!("Vorbis quality must be between 0 and 10, not {}", quality)));
+            }
+            (Target::Vorbis, None, None) => args.extend(["-q:a".to_string(), "6".to_string()]),
+            (target, Some(_), _) => {
+                return Err(Error::InvalidInput(format!(
+                    "A quality level is not supported for {}; use a bitrate instead", target.extension()
+                )));
+            }
+            (target, None, bitrate) => {
+                let bitrate = bitrate.unwrap_or(match target {
+                    Target::Opus => 160,
+                    _ => 256,
+                });
+                args.extend(["-b:a".to_string(), format!("{}k", bitrate)]);
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn mp3_mode(&self) -> Result<Mp3Mode> {
+        if let Some(preset) = self.preset {
+            if self.quality.is_some() || self.bitrate.is_some() || self.abr {
+                return Err(Error::InvalidInput(format!(
+                    "The {} preset cannot be combined with a bitrate, quality or ABR", preset.name()
+                )));
+            }
+            return Ok(preset.mode());
+        }
+
+        match (self.quality, self.abr) {
+            (Some(_), true) => Err(Error::InvalidInput("Choose either a VBR quality or ABR, not both".to_string())),
+            (Some(quality), false) if quality <= 9 => Ok(Mp3Mode::Vbr(quality)),
+            (Some(quality), false) => {
+                Err(Error::InvalidInput(format!("MP3 VBR quality must be between V0 and V9, not V{}", quality)))
+            }
+            (None, true) => Ok(Mp3Mode::Abr(self.bitrate.unwrap_or(320))),
+            (None, false) => Ok(Mp3Mode::Cbr(self.bitrate.unwrap_or(320))),
+        }
+    }
+
+    /// Encoder settings recorded in the output's encoder tag (ID3 TSSE)
+    fn encoder_settings(&self) -> Result<Option<String>> {
+        if self.target != Target::Mp3 {
+            return Ok(None);
+        }
+        let mode = self.mp3_mode()?.describe();
+        Ok(Some(match self.preset {
+            Some(preset) => format!("LAME {} (preset {})", mode, preset.name()),
+            None => format!("LAME {}", mode),
+        }))
+    }
+}
+
+/// Where the converted file for `input_path` is written: next to the input,
+/// or in `output_dir` if one is given
+pub fn output_path(input_path: &Path, output_dir: Option<&Path>, target: Target) -> PathBuf {
+    let stem = Path::new(input_path.file_stem().unwrap());
+    match output_dir {
+        Some(dir) => dir.join(stem).with_extension(target.extension()),
+        None => input_path.with_extension(target.extension()),
+    }
+}
+
+/// Convert an audio file with ffmpeg, carrying its tags over into the output
+/// container's native metadata. The input is backed up first, and the output
+/// only appears (or replaces an existing file) once it is complete.
+pub fn convert_file(input_path: &Path, output_path: &Path, options: &ConvertOptions, backup: &BackupRun) -> Result<()> {
+    let encoder_args = options.encoder_args()?;
+    check_input(input_path)?;
+    if output_path == input_path {
+        return Err(Error::InvalidInput(format!(
+            "Converting {} would overwrite it; choose an output directory", input_path.display()
+        )));
+    }
+
+    // Tags that can be read natively are mapped explicitly into the output's
+    // own scheme; those of other inputs are left to ffmpeg's mapping
+    let tag = match format_for_path(input_path) {
+        Ok(format) => Some(format.read_tag(input_path)?),
+        Err(_) => None,
+    };
+
+    // Backup the original file
+    backup.back_up(input_path)?;
+
+    // ffmpeg writes next to the output, which is only replaced once the conversion is complete
+    let temp = TempFile::new(output_path);
+    let metadata = TempFile::new(&output_path.with_extension("ffmetadata"));
+    let mut args = vec![
+        "-y",
+        "-loglevel", "error",
+        "-i", input_path.to_str().unwrap(),
+    ];
+    match &tag {
+        Some(tag) if matches!(options.target, Target::Aac | Target::Alac) => {
+            fs::write(metadata.path(), ffmetadata(tag, options.target)).map_err(Error::io(metadata.path()))?;
+            args.extend(["-f", "ffmetadata", "-i", metadata.path().to_str().unwrap(), "-map_metadata", "1"]);
+        }
+        _ => args.extend(["-map_metadata", "0"]),
+    }
+    args.extend(["-map", "0:a"]);
+    if options.target.keeps_cover_art() {
+        // Copy an embedded cover (an attached picture stream) unchanged, if there is one
+        args.extend(["-map", "0:v?", "-codec:v", "copy", "-disposition:v", "attached_pic"]);
+    }
+    args.extend(encoder_args.iter().map(String::as_str));
+    args.push(temp.path().to_str().unwrap());
+
+    tool::run("ffmpeg", &args, input_path)?;
+    match (&tag, options.target) {
+        (Some(tag), Target::Mp3) => format_for_path(temp.path())?.write_tag(temp.path(), tag, &WriteOptions::default())?,
+        (Some(tag), Target::Opus | Target::Vorbis) => ogg::update(temp.path(), |comments| comments.comments = ogg_comments(tag))?,
+        _ => {}
+    }
+    record_encoder_settings(temp.path(), options)?;
+    temp.commit()
+}
+
+/// An ffmpeg metadata file giving an M4A output the tags of `tag` in the atoms
+/// MP4 has for them (see `Target::metadata_key`). An atom holds one value, so
+/// several values are joined with "; ".
+fn ffmetadata(tag: &Tag, target: Target) -> String {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for field in tag.fields.keys() {
+        if let (Some(key), Some(values)) = (target.metadata_key(*field), tag.joined_number(*field)) {
+            entries.push((key.to_string(), values.join("; ")));
+        }
+    }
+
+    let mut text = ";FFMETADATA1\n".to_string();
+    for (key, value) in entries {
+        text.push_str(&format!("{}={}\n", escape_ffmetadata(&key), escape_ffmetadata(&value)));
+    }
+    text
+}
+
+/// The Vorbis comments of an Opus or Vorbis output: one comment per value of
+/// each field and custom tag, and the front cover (or else the first picture)
+/// as a METADATA_BLOCK_PICTURE comment
+fn ogg_comments(tag: &Tag) -> Vec<(String, String)> {
+    let mut comments = Vec::new();
+    for (field, values) in &tag.fields {
+        comments.extend(values.iter().map(|value| (field.vorbis_key().to_string(), value.clone())));
+    }
+    comments.extend(tag.custom.iter().cloned());
+    let cover = tag.pictures.iter().find(|p| p.picture_type == Picture::FRONT_COVER).or(tag.pictures.first());
+    if let Some(picture) = cover {
+        comments.push(("METADATA_BLOCK_PICTURE".to_string(), base64(&picture_to_bytes(picture))));
+    }
+    comments
+}
+
+/// Escape the characters with a meaning in ffmpeg metadata files
+fn escape_ffmetadata(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Standard base64 with padding, as METADATA_BLOCK_PICTURE comments use
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Store the encoder settings in the converted file, replacing ffmpeg's own
+/// encoder tag, which only names the library version
+fn record_encoder_settings(output_path: &Path, options: &ConvertOptions) -> Result<()> {
+    match options.encoder_settings()? {
+        Some(settings) => id3::update(output_path, |tag| {
+            let version = tag.version;
+            tag.replace(Frame::text("TSSE", &settings, version));
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Describe what `convert_file` would do: the output path, the encoder settings
+/// and how the output's tags would change. Nothing is written.
+pub fn preview_conversion(input_path: &Path, output_path: &Path, options: &ConvertOptions) -> Result<String> {
+    let encoder_args = options.encoder_args()?;
+    check_input(input_path)?;
+    let settings = match options.encoder_settings()? {
+        Some(settings) => settings,
+        None => encoder_args.join(" "),
+    };
+    let mut lines = vec![format!("Would convert {} to {} ({})", input_path.display(), output_path.display(), settings)];
+
+    // Tags can only be previewed where the input and output formats are readable natively
+    let source = format_for_path(input_path).and_then(|format| format.read_tag(input_path));
+    let output_format = format_for_path(output_path);
+    match (source, output_format) {
+        (Ok(mut after), Ok(output_format)) => {
+            let before = if output_path.exists() {
+                output_format.read_tag(output_path)?
+            } else {
+                Tag::default()
+            };
+            if !options.target.keeps_cover_art() {
+                after.pictures.clear();
+            }
+            lines.extend(before.diff(&after));
+        }
+        _ => lines.push("tags are copied by ffmpeg and cannot be previewed for this format".to_string()),
+    }
+    if output_path.exists() {
+        lines.push("the existing output file would be replaced".to_string());
+    }
+
+    Ok(lines.join("\n  "))
+}
+
+/// Convert a single audio file, backing it up first, and pass its outcome to `progress`
+pub fn convert_single_file(
+    input_path: &Path,
+    output_dir: Option<&Path>,
+    options: &ConvertOptions,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
+) -> Result<()> {
+    let output_path = output_path(input_path, output_dir, options.target);
+    let message = if options.dry_run {
+        preview_conversion(input_path, &output_path, options)?
+    } else {
+        convert_file(input_path, &output_path, options, backup)?;
+        format!("Successfully converted {} to {}", input_path.display(), output_path.display())
+    };
+    progress(Progress::File { path: input_path.to_path_buf(), result: Ok(message) });
+    Ok(())
+}
+
+/// Check that a file is one of the supported input formats
+fn check_input(path: &Path) -> Result<()> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    if !INPUT_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(Error::UnsupportedFormat { path: path.to_path_buf() });
+    }
+
+    // M4A files may hold lossy AAC, which is not worth converting again
+    if extension == "m4a" {
+        let codec = tool::output("ffprobe", &[
+            "-loglevel", "error",
+            "-select_streams", "a:0",
+            "-show_entries", "stream=codec_name",
+            "-of", "csv=p=0",
+            path.to_str().unwrap(),
+        ], path)?;
+        if codec.trim() != "alac" {
+            return Err(Error::InvalidInput(format!(
+                "{} holds {} audio; only ALAC M4A files can be converted", path.display(), codec.trim()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoder_args() {
+        let args = |target, bitrate, quality| {
+            ConvertOptions { target, bitrate, quality, ..ConvertOptions::default() }
+                .encoder_args()
+                .map(|args| args.join(" "))
+        };
+
+        assert_eq!(args(Target::Mp3, None, None).unwrap(), "-codec:a libmp3lame -b:a 320k");
+        assert_eq!(args(Target::Opus, Some(96), None).unwrap(), "-codec:a libopus -b:a 96k");
+        assert_eq!(args(Target::Aac, None, None).unwrap(), "-codec:a aac -b:a 256k");
+        assert_eq!(args(Target::Vorbis, None, None).unwrap(), "-codec:a libvorbis -q:a 6");
+        assert_eq!(args(Target::Vorbis, Some(192), Some(4)).unwrap(), "-codec:a libvorbis -q:a 4");
+        assert_eq!(args(Target::Alac, None, None).unwrap(), "-codec:a alac");
+        assert!(args(Target::Vorbis, None, Some(11)).is_err());
+        assert!(args(Target::Opus, None, Some(5)).is_err());
+        assert!(args(Target::Alac, Some(320), None).is_err());
+    }
+
+    #[test]
+    fn test_mp3_modes() {
+        let describe = |options: ConvertOptions| {
+            let args = options.encoder_args().map(|args| args.join(" "));
+            args.and_then(|args| Ok(format!("{} | {}", args, options.encoder_settings()?.unwrap())))
+        };
+
+        assert_eq!(describe(ConvertOptions { quality: Some(2), ..ConvertOptions::default() }).unwrap(),
+            "-codec:a libmp3lame -q:a 2 | LAME VBR V2");
+        assert_eq!(describe(ConvertOptions { bitrate: Some(192), abr: true, ..ConvertOptions::default() }).unwrap(),
+            "-codec:a libmp3lame -abr 1 -b:a 192k | LAME ABR 192 kbps");
+        assert_eq!(describe(ConvertOptions { preset: Some(Mp3Preset::ArchiveTransparent), ..ConvertOptions::default() }).unwrap(),
+            "-codec:a libmp3lame -q:a 0 | LAME VBR V0 (preset archive-transparent)");
+        assert!(describe(ConvertOptions { quality: Some(10), ..ConvertOptions::default() }).is_err());
+        assert!(describe(ConvertOptions { quality: Some(2), abr: true, ..ConvertOptions::default() }).is_err());
+        assert!(describe(ConvertOptions { preset: Some(Mp3Preset::Portable), bitrate: Some(128), ..ConvertOptions::default() }).is_err());
+        assert!(ConvertOptions { target: Target::Opus, abr: true, ..ConvertOptions::default() }.encoder_args().is_err());
+    }
+
+    #[test]
+    fn test_output_metadata() {
+        let mut tag = Tag::default();
+        tag.fields.insert(Field::Artist, vec!["A".to_string(), "B".to_string()]);
+        tag.fields.insert(Field::Title, vec!["Why; = #1".to_string()]);
+        tag.fields.insert(Field::TrackNumber, vec!["3".to_string()]);
+        tag.fields.insert(Field::TrackTotal, vec!["12".to_string()]);
+        tag.fields.insert(Field::Isrc, vec!["USS1Z9900001".to_string()]);
+        tag.custom.push(("CATALOGNUMBER".to_string(), "XYZ-1".to_string()));
+        tag.set_picture(Picture {
+            picture_type: Picture::FRONT_COVER,
+            mime_type: "image/png".to_string(),
+            description: String::new(),
+            width: 1,
+            height: 1,
+            color_depth: 24,
+            colors: 0,
+            data: vec![0x89, b'P', b'N', b'G'],
+        });
+
+        // Ogg gets each value as its own comment
+        let picture = base64(&picture_to_bytes(&tag.pictures[0]));
+        let comments: Vec<String> = ogg_comments(&tag).into_iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        assert_eq!(comments, [
+            "ARTIST=A", "ARTIST=B", "TITLE=Why; = #1", "TRACKNUMBER=3", "TRACKTOTAL=12", "ISRC=USS1Z9900001",
+            "CATALOGNUMBER=XYZ-1", &format!("METADATA_BLOCK_PICTURE={}", picture),
+        ]);
+
+        // MP4 keeps the total with the number, and has no atoms for ISRCs or custom tags
+        assert_eq!(ffmetadata(&tag, Target::Aac), ";FFMETADATA1\nartist=A\\; B\ntitle=Why\\; \\= \\#1\ntrack=3/12\n");
+
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::atomic;
+use crate::backup::BackupRun;
+use crate::error::{Error, Result};
+use crate::file_ops::{SUPPORTED_EXTENSIONS, preview_file, process_file};
+use crate::format::{WriteOptions, format_for_path};
+use crate::picture::{Picture, picture_type_name};
+use crate::progress::{Progress, Tally};
+use crate::tag::TagUpdate;
+use crate::walk::{WalkOptions, collect_file_groups};
+
+/// What to do with the embedded pictures of each file
+#[derive(Debug, Clone)]
+pub enum CoverAction {
+    /// Save the pictures as image files in this directory (subdirectories are
+    /// mirrored), or next to each audio file
+    Extract { output_dir: Option<PathBuf> },
+    /// Embed a picture, replacing any picture of the same type
+    Set(Picture),
+    /// Remove the pictures
+    Remove,
+}
+
+/// Options for the `cover` subcommand
+#[derive(Debug, Clone)]
+pub struct CoverOptions {
+    pub action: CoverAction,
+    /// Picture types to extract or remove, e.g. `Picture::FRONT_COVER`; all
+    /// types if empty
+    pub types: Vec<u32>,
+    /// Print what would be done instead of doing it
+    pub dry_run: bool,
+}
+
+/// Extract, embed or remove the pictures of an audio file, or of the audio
+/// files in a directory. Files are backed up in `backup` before they are
+/// changed, and each file's outcome is passed to `progress`. A single file's
+/// error is returned as is; for a directory, `Error::PartialFailure` if any
+/// file failed.
+pub fn process_covers(
+    path: &Path,
+    options: &CoverOptions,
+    walk: &WalkOptions,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
+) -> Result<()> {
+    let files: Vec<PathBuf> = if path.is_dir() {
+        collect_file_groups(path, &SUPPORTED_EXTENSIONS, walk)?.into_iter().flatten().collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let root = if path.is_dir() { path } else { path.parent().unwrap_or(Path::new("")) };
+
+    let mut update = TagUpdate::default();
+    match &options.action {
+        CoverAction::Set(picture) => update.pictures.push(picture.clone()),
+        CoverAction::Remove if options.types.is_empty() => update.remove_pictures = true,
+        CoverAction::Remove => update.remove_picture_types.extend(&options.types),
+        CoverAction::Extract { .. } => {}
+    }
+    let write = WriteOptions::default();
+
+    let mut tally = Tally::new(progress);
+    for file in &files {
+        let result = match &options.action {
+            CoverAction::Extract { output_dir } => {
+                // Pictures go next to the file, or into the same subdirectory of the output directory
+                let parent = file.parent().unwrap_or(Path::new(""));
+                let dir = match output_dir {
+                    Some(dir) => dir.join(parent.strip_prefix(root).unwrap_or(Path::new(""))).components().collect(),
+                    None => parent.to_path_buf(),
+                };
+                extract_pictures(file, &dir, &options.types, options.dry_run)
+            }
+            _ if options.dry_run => preview_file(file, &update, &write),
+            _ => process_file(file, &update, &write, backup),
+        };
+        match result {
+            Err(e) if !path.is_dir() => return Err(e),
+            result => tally.file(file.clone(), result),
+        }
+    }
+
+    tally.finish()
+}
+
+/// Save the pictures of `file` with one of `types` (or all of them) into
+/// `dir`, returning a line of progress output
+fn extract_pictures(file: &Path, dir: &Path, types: &[u32], dry_run: bool) -> Result<String> {
+    let tag = format_for_path(file)?.read_tag(file)?;
+    let pictures: Vec<&Picture> = tag.pictures.iter()
+        .filter(|picture| types.is_empty() || types.contains(&picture.picture_type))
+        .collect();
+    if pictures.is_empty() {
+        return Ok(format!("No pictures to extract from {}", file.display()));
+    }
+
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let mut names: Vec<String> = Vec::new();
+    for picture in pictures {
+        let name = picture_file_name(&stem, picture, &names);
+        if !dry_run {
+            fs::create_dir_all(dir).map_err(Error::io(dir))?;
+            atomic::write(&dir.join(&name), &picture.data)?;
+        }
+        names.push(name);
+    }
+    let verb = if dry_run { "Would extract" } else { "Extracted" };
+    Ok(format!("{} {} to {}: {}", verb, file.display(), dir.display(), names.join(", ")))
+}
+
+/// File name for a picture extracted from the audio file named `stem`, e.g.
+/// `01 - Song - front cover.jpg`, made unique among the names already `taken`
+fn picture_file_name(stem: &str, picture: &Picture, taken: &[String]) -> String {
+    let extension = picture_extension(&picture.mime_type);
+    let base = format!("{} - {}", stem, picture_type_name(picture.picture_type));
+    let mut name = format!("{}.{}", base, extension);
+    let mut count = 1;
+    while taken.contains(&name) {
+        count += 1;
+        name = format!("{} ({}).{}", base, count, extension);
+    }
+    name
+}
+
+/// File extension for an image of this MIME type. The MIME type comes from
+/// the audio file, so an unknown one only keeps its letters and digits and can
+/// never add a path separator or `..` to the file name.
+fn picture_extension(mime_type: &str) -> String {
+    let mime_type = mime_type.to_lowercase();
+    match mime_type.as_str() {
+        "image/jpeg" | "image/jpg" => "jpg".to_string(),
+        "image/png" | "image/gif" | "image/webp" | "image/bmp" | "image/tiff" => mime_type[6..].to_string(),
+        _ => {
+            let subtype: String = mime_type.strip_prefix("image/").unwrap_or("")
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect();
+            if subtype.is_empty() { "bin".to_string() } else { subtype }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_picture_file_name() {
+        let picture = |picture_type, mime_type: &str| Picture {
+            picture_type,
+            mime_type: mime_type.to_string(),
+            description: String::new(),
+            width: 0,
+            height: 0,
+            color_depth: 0,
+            colors: 0,
+            data: Vec::new(),
+        };
+        let front = picture(Picture::FRONT_COVER, "image/jpeg");
+        assert_eq!(picture_file_name("01 - Song", &front, &[]), "01 - Song - front cover.jpg");
+        let taken = vec!["01 - Song - front cover.jpg".to_string()];
+        assert_eq!(picture_file_name("01 - Song", &front, &taken), "01 - Song - front cover (2).jpg");
+        assert_eq!(picture_file_name("Song", &picture(4, "image/png"), &[]), "Song - back cover.png");
+        assert_eq!(picture_file_name("Song", &picture(5, "image/webp"), &[]), "Song - leaflet page.webp");
+        assert_eq!(picture_file_name("Song", &picture(0, "-->"), &[]), "Song - other.bin");
+
+        // MIME types read from a file must not take the picture out of the output directory
+        assert_eq!(picture_file_name("Song", &picture(3, "image/../../pwned"), &[]), "Song - front cover.pwned");
+        assert_eq!(picture_file_name("Song", &picture(3, "image/./"), &[]), "Song - front cover.bin");
+        assert_eq!(picture_file_name("Song", &picture(3, "image/x\\..\\y"), &[]), "Song - front cover.xy");
+    }
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Errors returned by the library API
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The file's extension is not one of the supported audio formats
+    #[error("Unsupported file format: {}", .path.display())]
+    UnsupportedFormat { path: PathBuf },
+
+    /// An external program needed for the operation could not be run
+    #[error("{tool} is not installed or not on the PATH")]
+    MissingTool { tool: String },
+
+    /// An external program ran but reported failure
+    #[error("{tool} failed on {} ({}){}", .path.display(), exit_status(*.code), stderr_suffix(.stderr))]
+    ToolFailed {
+        tool: String,
+        path: PathBuf,
+        /// Exit code, or `None` if the program was killed by a signal
+        code: Option<i32>,
+        /// Captured standard error output, trimmed
+        stderr: String,
+    },
+
+    /// Reading or writing a file failed
+    #[error("I/O error on {}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// Backing up a file (or restoring it from its backup) failed
+    #[error("Backup of {} failed: {source}", .path.display())]
+    Backup { path: PathBuf, source: io::Error },
+
+    /// A file contains tag or image data that cannot be parsed
+    #[error("Invalid {format} data in {}: {message}", .path.display())]
+    Parse {
+        path: PathBuf,
+        /// What was being parsed, e.g. "ID3v2" or "FLAC"
+        format: &'static str,
+        message: String,
+    },
+
+    /// The input does not meet the operation's requirements
+    #[error("{0}")]
+    InvalidInput(String),
+
+    /// Some files of a run failed; each failure was passed to the progress callback
+    #[error("{failed} of {total} files failed")]
+    PartialFailure { failed: usize, total: usize },
+}
+
+impl Error {
+    /// Wrap an I/O error with the path it occurred on, for use with `map_err`
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Io { path: path.to_path_buf(), source }
+    }
+
+    /// Wrap an I/O error from a backup operation, for use with `map_err`
+    pub(crate) fn backup(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Backup { path: path.to_path_buf(), source }
+    }
+
+    /// Process exit code the CLI uses for this kind of error. 1 is reserved
+    /// for failures outside the library and 2 for command-line usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::UnsupportedFormat { .. } | Error::InvalidInput(_) => 3,
+            Error::MissingTool { .. } => 4,
+            Error::ToolFailed { .. } => 5,
+            Error::Io { .. } => 6,
+            Error::Backup { .. } => 7,
+            Error::Parse { .. } => 8,
+            Error::PartialFailure { .. } => 9,
+        }
+    }
+}
+
+fn exit_status(code: Option<i32>) -> String {
+    match code {
+        Some(code) => format!("exit code {}", code),
+        None => "killed by signal".to_string(),
+    }
+}
+
+fn stderr_suffix(stderr: &str) -> String {
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", stderr)
+    }
+}
+
+/// Malformed tag or image data, before it has been attributed to a file
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct ParseError(pub String);
+
+impl ParseError {
+    /// Attribute the error to a file, for use with `map_err`
+    pub(crate) fn in_file<'a>(path: &'a Path, format: &'static str) -> impl FnOnce(ParseError) -> Error + 'a {
+        move |e| Error::Parse { path: path.to_path_buf(), format, message: e.0 }
+    }
+}
+
+/// Result type of the format parsers, before errors are attributed to a file
+pub(crate) type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// Result type used throughout the library API
+pub type Result<T> = std::result::Result<T, Error>;
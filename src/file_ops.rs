@@ -1,217 +1,616 @@
-use anyhow::{Result, Context};
-use std::path::PathBuf;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::LazyLock;
+use crate::backup::BackupRun;
+use crate::convert::{self, ConvertOptions, INPUT_EXTENSIONS};
+use crate::error::{Error, Result};
+use crate::format::{WriteOptions, format_for_path};
 use crate::metadata;
+use crate::pattern::FilenamePattern;
+use crate::picture::{Picture, picture_type_name};
+use crate::pool;
+use crate::progress::{Progress, Tally};
+use crate::tag::{Field, Tag, TagItem, TagUpdate};
+use crate::walk::{WalkOptions, collect_file_groups};
 
-/// Process a directory of audio files, setting cover art, album title, artist, and/or song title
+/// Extensions of the audio files that can be tagged
+pub(crate) const SUPPORTED_EXTENSIONS: [&str; 2] = ["mp3", "flac"];
+
+/// List the supported audio files directly inside a directory, sorted by name
+pub fn collect_audio_files(dir_path: &Path) -> Result<Vec<PathBuf>> {
+    let groups = collect_file_groups(dir_path, &SUPPORTED_EXTENSIONS, &WalkOptions::default())?;
+    Ok(groups.into_iter().flatten().collect())
+}
+
+/// Where the cover art embedded by `set` comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoverSource {
+    /// An image file, embedded as the front cover of every file
+    File(PathBuf),
+    /// The best cover image in each audio file's directory, embedded only into
+    /// files that have no pictures yet
+    Auto,
+}
+
+impl From<PathBuf> for CoverSource {
+    /// `auto` stands for `CoverSource::Auto`; anything else is an image file,
+    /// so an image literally named `auto` is given as `./auto`
+    fn from(path: PathBuf) -> Self {
+        if path.as_os_str() == "auto" { CoverSource::Auto } else { CoverSource::File(path) }
+    }
+}
+
+/// Metadata to apply to each file processed by `set`
+#[derive(Debug, Default)]
+pub struct SetOptions {
+    pub cover: Option<CoverSource>,
+    pub album: Option<String>,
+    /// Artists to set; several make a multi-valued field
+    pub artist: Vec<String>,
+    pub title: Option<String>,
+    pub album_artist: Vec<String>,
+    /// Release date, e.g. `1999` or `1999-03-24`
+    pub date: Option<String>,
+    pub genre: Vec<String>,
+    pub composer: Vec<String>,
+    pub performer: Vec<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<u32>,
+    pub isrc: Option<String>,
+    pub copyright: Option<String>,
+    /// Unsynchronised lyrics
+    pub lyrics: Option<String>,
+    /// Tags to set as `(KEY, VALUE)`: standard field names such as `GENRE` set
+    /// that field, and any other key becomes a custom tag (TXXX in MP3 files)
+    pub tags: Vec<(String, String)>,
+    /// Tag keys to remove, standard field names or custom keys
+    pub remove_tags: Vec<String>,
+    /// Fields, custom tags or pictures to remove
+    pub clear: Vec<TagItem>,
+    /// Remove everything except these items and the fields being set,
+    /// including pictures, unknown ID3v2 frames and ID3v1 trailers.
+    /// `Some(vec![])` strips all tags.
+    pub keep: Option<Vec<TagItem>>,
+    pub track: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc: Option<u32>,
+    pub disc_total: Option<u32>,
+    /// Infer the title from the filename
+    pub infer_track: bool,
+    /// Read fields from the file and directory names with this template,
+    /// e.g. `%n - %a - %t` (see `FilenamePattern`)
+    pub from_filename: Option<String>,
+    /// Number tracks by their sorted position in their directory, set the
+    /// track total, and take disc numbers from `CD1`, `Disc 2`, ... directories
+    pub infer_order: bool,
+    /// Print the changes that would be made instead of making them
+    pub dry_run: bool,
+    pub write: WriteOptions,
+}
+
+/// Process a directory of audio files, setting cover art, album title, artist, and/or song title.
+/// Up to `jobs` files are processed at once, and each file's outcome is passed to `progress`.
+/// Returns `Error::PartialFailure` if any file failed.
 pub fn process_directory(
-    dir_path: &PathBuf,
-    cover_path: Option<PathBuf>,
-    album_title: Option<&str>,
-    artist: Option<&str>,
-    title: Option<&str>,
-    track: Option<u32>,
-    infer_track: bool,
-    infer_order: bool,
-    temp_dir: &PathBuf
+    dir_path: &Path,
+    options: &SetOptions,
+    walk: &WalkOptions,
+    jobs: usize,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
 ) -> Result<()> {
-    let supported_extensions = ["mp3", "flac"];
-    let mut error_count = 0;
-    
-    // If infer_order is enabled, collect and sort files first
-    if infer_order {
-        let mut audio_files: Vec<PathBuf> = Vec::new();
-        
-        for entry in fs::read_dir(dir_path)
-            .with_context(|| format!("Failed to read directory: {}", dir_path.display()))? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if let Some(ext_str) = ext.to_str() {
-                        if supported_extensions.contains(&ext_str.to_lowercase().as_str()) {
-                            audio_files.push(path);
-                        }
-                    }
-                }
-            }
+    let cover = load_cover(options)?;
+    let pattern = parse_pattern(options)?;
+    let write = write_options(options);
+    let mut tally = Tally::new(progress);
+
+    // Covers found in each directory, referred to by index from `files`
+    let mut found_covers = Vec::new();
+    let mut files = Vec::new();
+    for audio_files in collect_file_groups(dir_path, &SUPPORTED_EXTENSIONS, walk)? {
+        let dir = audio_files.first().and_then(|path| path.parent()).unwrap_or(Path::new(""));
+        if options.cover == Some(CoverSource::Auto) {
+            found_covers.push(auto_cover(dir, &mut |message| tally.warning(message)));
         }
-        
-        // Sort files by name
-        audio_files.sort();
-        
-        // Process files with track numbers
-        for (index, path) in audio_files.iter().enumerate() {
-            let track_number = index + 1;
-            
-            // Backup the file
-            let backup_path = temp_dir.join(path.file_name().unwrap());
-            if let Err(e) = fs::copy(path, &backup_path) {
-                eprintln!("Error backing up {}: {}", path.display(), e);
-                error_count += 1;
-                continue;
-            }
+        let numbering = group_numbering(&audio_files, options);
+        for (path, numbering) in audio_files.into_iter().zip(numbering) {
+            files.push((path, numbering, found_covers.len().checked_sub(1)));
+        }
+    }
 
-            // Set track number
-            if let Err(e) = metadata::set_track_number(path, track_number as u32) {
-                eprintln!("Error setting track number for {}: {}", path.display(), e);
-                error_count += 1;
-            } else {
-                println!("Set track number {} for {}", track_number, path.display());
-            }
+    pool::for_each_ordered(&files, jobs, |(path, numbering, found)| {
+        let cover = cover.as_ref().or(found.and_then(|index| found_covers[index].as_ref()));
+        let update = build_update(path, options, cover, pattern.as_ref(), *numbering)?;
+        if options.dry_run {
+            return preview_file(path, &update, &write);
+        }
+        process_file(path, &update, &write, backup)
+    }, |(path, ..), result| tally.file(path.clone(), result));
 
-            // Process other metadata operations
-            process_single_file_metadata(path, &cover_path, album_title, artist, title, track, infer_track, temp_dir, &mut error_count)?;
+    tally.finish()
+}
+
+/// Process a single audio file, backing it up first, and pass its outcome to `progress`
+pub fn process_single_file(
+    path: &Path,
+    options: &SetOptions,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
+) -> Result<()> {
+    let cover = match options.cover {
+        Some(CoverSource::Auto) => {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            auto_cover(dir, &mut |message| progress(Progress::Warning(message)))
         }
+        _ => load_cover(options)?,
+    };
+    let update = build_update(path, options, cover.as_ref(), parse_pattern(options)?.as_ref(), Numbering::from(options))?;
+    let message = if options.dry_run {
+        preview_file(path, &update, &write_options(options))?
     } else {
-        // Process files without track number inference (original behavior)
-        for entry in fs::read_dir(dir_path)
-            .with_context(|| format!("Failed to read directory: {}", dir_path.display()))? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if let Some(ext_str) = ext.to_str() {
-                        if supported_extensions.contains(&ext_str.to_lowercase().as_str()) {
-                            // Backup the file
-                            let backup_path = temp_dir.join(path.file_name().unwrap());
-                            if let Err(e) = fs::copy(&path, &backup_path) {
-                                eprintln!("Error backing up {}: {}", path.display(), e);
-                                error_count += 1;
-                                continue;
-                            }
-
-                            process_single_file_metadata(&path, &cover_path, album_title, artist, title, track, infer_track, temp_dir, &mut error_count)?;
-                        }
-                    }
-                }
-            }
+        process_file(path, &update, &write_options(options), backup)?
+    };
+    progress(Progress::File { path: path.to_path_buf(), result: Ok(message) });
+    Ok(())
+}
+
+/// Track and disc numbers to set on one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Numbering {
+    track: Option<u32>,
+    track_total: Option<u32>,
+    disc: Option<u32>,
+    disc_total: Option<u32>,
+}
+
+impl From<&SetOptions> for Numbering {
+    fn from(options: &SetOptions) -> Self {
+        Numbering {
+            track: options.track,
+            track_total: options.track_total,
+            disc: options.disc,
+            disc_total: options.disc_total,
         }
     }
+}
 
-    if error_count > 0 {
-        println!("\nCompleted with {} errors. Check the messages above for details.", error_count);
+/// Track and disc numbers for a group of files from the same directory, in
+/// order. With `infer_order`, track numbers follow the sorted order of the
+/// files, starting again at 1 in each (album or disc) directory. Totals and
+/// discs given explicitly take precedence over inferred ones.
+fn group_numbering(files: &[PathBuf], options: &SetOptions) -> Vec<Numbering> {
+    if !options.infer_order {
+        return vec![Numbering::from(options); files.len()];
     }
+    let total = files.len() as u32;
+    let disc = files.first().and_then(|path| path.parent()).and_then(disc_folder);
+    (1..=total).map(|track| Numbering {
+        track: Some(track),
+        track_total: options.track_total.or(Some(total)),
+        disc: options.disc.or(disc.map(|(number, _)| number)),
+        disc_total: options.disc_total.or(disc.map(|(_, total)| total)),
+    }).collect()
+}
 
-    Ok(())
+/// Names of directories holding one disc of an album, e.g. `CD1`, `cd 2` or `Disc 03`
+static DISC_FOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)^(?:cd|dis[ck])[\s._-]*(\d+)$").unwrap());
+
+fn disc_folder_number(dir: &Path) -> Option<u32> {
+    let name = dir.file_name()?.to_str()?;
+    DISC_FOLDER.captures(name)?[1].parse().ok()
 }
 
-/// Process metadata for a single audio file
-fn process_single_file_metadata(
-    path: &PathBuf,
-    cover_path: &Option<PathBuf>,
-    album_title: Option<&str>,
-    artist: Option<&str>,
-    title: Option<&str>,
-    track: Option<u32>,
-    infer_track: bool,
-    temp_dir: &PathBuf,
-    error_count: &mut i32
-) -> Result<()> {
-    // Set cover art if provided
-    if let Some(cover) = cover_path {
-        if let Err(e) = metadata::set_cover_art_with_temp(path, cover, temp_dir) {
-            eprintln!("Error setting cover art for {}: {}", path.display(), e);
-            *error_count += 1;
+/// The disc number of a disc directory, and the number of discs: the highest
+/// number among it and its sibling disc directories
+fn disc_folder(dir: &Path) -> Option<(u32, u32)> {
+    let number = disc_folder_number(dir)?;
+    let siblings = dir.parent().and_then(|parent| fs::read_dir(parent).ok());
+    let total = siblings.into_iter().flatten().flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| disc_folder_number(&entry.path()))
+        .fold(number, u32::max);
+    Some((number, total))
+}
+
+/// The cover image given explicitly, if any
+fn load_cover(options: &SetOptions) -> Result<Option<Picture>> {
+    match &options.cover {
+        Some(CoverSource::File(path)) => metadata::load_cover_art(path).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Names of image files holding an album's front cover, best first
+const COVER_NAMES: [&str; 5] = ["cover", "folder", "front", "album", "albumart"];
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "gif"];
+
+/// How good a cover candidate an image file is, lower being better: exact
+/// names such as `cover.jpg` come first, in `COVER_NAMES` order, then names
+/// containing one of them as a word, such as `Album - Cover.png`. Images that
+/// mention the back (`cover back.jpg`) are never candidates.
+fn cover_rank(path: &Path) -> Option<usize> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    let words: Vec<&str> = stem.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+    if words.contains(&"back") {
+        return None;
+    }
+    COVER_NAMES.iter().position(|&name| name == stem)
+        .or_else(|| COVER_NAMES.iter().position(|name| words.contains(name)).map(|rank| rank + COVER_NAMES.len()))
+}
+
+/// The best cover image in `dir` by `cover_rank`, preferring the highest
+/// resolution among equally named images. A disc directory (`CD1`) without
+/// one uses its album directory's.
+fn find_cover(dir: &Path) -> Result<Option<Picture>> {
+    let mut candidates = Vec::new();
+    for entry in fs::read_dir(dir).map_err(Error::io(dir))? {
+        let path = entry.map_err(Error::io(dir))?.path();
+        if let Some(rank) = cover_rank(&path).filter(|_| path.is_file()) {
+            candidates.push((rank, path));
         }
     }
+    candidates.sort();
 
-    // Set album title if provided
-    if let Some(album) = album_title {
-        if let Err(e) = metadata::set_album_title(path, album) {
-            eprintln!("Error setting album title for {}: {}", path.display(), e);
-            *error_count += 1;
+    let mut best: Option<(usize, Picture)> = None;
+    for (rank, path) in candidates {
+        if best.as_ref().is_some_and(|(best_rank, _)| *best_rank < rank) {
+            break;
+        }
+        // Images that cannot be read are passed over for the next best
+        let Ok(picture) = metadata::load_cover_art(&path) else {
+            continue;
+        };
+        let pixels = |picture: &Picture| picture.width as u64 * picture.height as u64;
+        if best.as_ref().is_none_or(|(_, current)| pixels(&picture) > pixels(current)) {
+            best = Some((rank, picture));
         }
     }
+    match best {
+        Some((_, picture)) => Ok(Some(picture)),
+        None => match dir.parent().filter(|_| disc_folder_number(dir).is_some()) {
+            Some(album_dir) => find_cover(album_dir),
+            None => Ok(None),
+        },
+    }
+}
 
-    // Set artist if provided
-    if let Some(artist_name) = artist {
-        if let Err(e) = metadata::set_artist(path, artist_name) {
-            eprintln!("Error setting artist for {}: {}", path.display(), e);
-            *error_count += 1;
+/// The cover `find_cover` picks for `dir`. If there is none, or the directory
+/// cannot be searched, its files are left without one and `warn` is told why.
+fn auto_cover(dir: &Path, warn: &mut dyn FnMut(String)) -> Option<Picture> {
+    match find_cover(dir) {
+        Ok(Some(picture)) => Some(picture),
+        Ok(None) => {
+            warn(format!("No cover image found in {}", dir.display()));
+            None
+        }
+        Err(e) => {
+            warn(format!("Could not look for a cover image in {}: {}", dir.display(), e));
+            None
         }
     }
+}
+
+/// Keeping a list of fields also drops the native data `Tag` cannot represent
+fn write_options(options: &SetOptions) -> WriteOptions {
+    WriteOptions {
+        remove_unmapped: options.write.remove_unmapped || options.keep.is_some(),
+        ..options.write.clone()
+    }
+}
+
+fn parse_pattern(options: &SetOptions) -> Result<Option<FilenamePattern>> {
+    options.from_filename.as_deref().map(FilenamePattern::parse).transpose()
+}
+
+/// Back up a file, then apply all requested changes to it in a single atomic
+/// write. Returns a line of progress output.
+pub(crate) fn process_file(path: &Path, update: &TagUpdate, write: &WriteOptions, backup: &BackupRun) -> Result<String> {
+    let format = format_for_path(path)?;
+    let before = format.read_tag_with(path, write)?;
+    let mut after = before.clone();
+    update.apply(&mut after);
+    let unmapped = removed_native(path, write)?;
+    if after == before && unmapped.is_empty() {
+        return Ok(format!("Nothing to change for {}", path.display()));
+    }
+
+    backup.back_up(path)?;
+    format.write_tag(path, &after, write)?;
+
+    Ok(format!("Updated {}: {}", path.display(), describe_update(update, &before, &after, &unmapped)))
+}
+
+/// Describe what `process_file` would change, without writing anything
+pub(crate) fn preview_file(path: &Path, update: &TagUpdate, write: &WriteOptions) -> Result<String> {
+    let format = format_for_path(path)?;
+    let before = format.read_tag_with(path, write)?;
+    let mut after = before.clone();
+    update.apply(&mut after);
+
+    let mut changes = before.diff(&after);
+    let unmapped = removed_native(path, write)?;
+    if !unmapped.is_empty() {
+        changes.push(format!("Other tags: {} -> (none)", unmapped.join(", ")));
+    }
+    if changes.is_empty() {
+        return Ok(format!("Nothing to change for {}", path.display()));
+    }
+    Ok(format!("Would update {}:\n  {}", path.display(), changes.join("\n  ")))
+}
 
-    // Set song title if provided
-    if let Some(song_title) = title {
-        if let Err(e) = metadata::set_title_with_temp(path, song_title, temp_dir) {
-            eprintln!("Error setting song title for {}: {}", path.display(), e);
-            *error_count += 1;
+/// Names of the native frames (and ID3v1 tag) a write with `write` removes
+fn removed_native(path: &Path, write: &WriteOptions) -> Result<Vec<String>> {
+    if !write.remove_unmapped {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = Vec::new();
+    for (name, _) in format_for_path(path)?.native_info(path)?.unmapped {
+        if !names.contains(&name) {
+            names.push(name);
         }
     }
+    Ok(names)
+}
+
+/// Build the tag changes for a single audio file. Fields read with a
+/// filename pattern are overridden by fields given explicitly.
+fn build_update(
+    path: &Path,
+    options: &SetOptions,
+    cover: Option<&Picture>,
+    pattern: Option<&FilenamePattern>,
+    numbering: Numbering
+) -> Result<TagUpdate> {
+    let mut update = TagUpdate::default();
 
-    // Set track number if provided
-    if let Some(track_number) = track {
-        if let Err(e) = metadata::set_track_number(path, track_number) {
-            eprintln!("Error setting track number for {}: {}", path.display(), e);
-            *error_count += 1;
-        } else {
-            println!("Set track number {} for {}", track_number, path.display());
+    if let Some(pattern) = pattern {
+        for (field, value) in pattern.extract(path)? {
+            update.set(field, value);
         }
     }
 
-    // Infer and set track name from filename if requested
-    if infer_track {
-        match metadata::infer_track_name_from_filename(path) {
-            Ok(inferred_title) => {
-                if let Err(e) = metadata::set_title_with_temp(path, &inferred_title, temp_dir) {
-                    eprintln!("Error setting inferred track name for {}: {}", path.display(), e);
-                    *error_count += 1;
-                } else {
-                    println!("Set track name to '{}' for {}", inferred_title, path.display());
-                }
+    update.keep = options.keep.clone();
+    for item in &options.clear {
+        match item {
+            TagItem::Field(field) => {
+                update.remove_fields.insert(*field);
             }
-            Err(e) => {
-                eprintln!("Error inferring track name for {}: {}", path.display(), e);
-                *error_count += 1;
+            TagItem::Custom(key) => update.remove_custom.push(key.clone()),
+            TagItem::Pictures => update.remove_pictures = true,
+        }
+    }
+    for key in &options.remove_tags {
+        match Field::from_vorbis_key(key) {
+            Some(field) => {
+                update.remove_fields.insert(field);
             }
+            None => update.remove_custom.push(key.clone()),
+        }
+    }
+    let mut tag_fields: BTreeMap<Field, Vec<String>> = BTreeMap::new();
+    for (key, value) in &options.tags {
+        match Field::from_vorbis_key(key) {
+            Some(field) => tag_fields.entry(field).or_default().push(value.clone()),
+            None => update.set_custom(key.as_str(), value.as_str()),
         }
     }
+    for (field, values) in tag_fields {
+        update.set_values(field, values);
+    }
 
-    Ok(())
+    let bpm = options.bpm.map(|bpm| bpm.to_string());
+    for (field, value) in [
+        (Field::Album, &options.album),
+        (Field::Title, &options.title),
+        (Field::Date, &options.date),
+        (Field::Comment, &options.comment),
+        (Field::Bpm, &bpm),
+        (Field::Isrc, &options.isrc),
+        (Field::Copyright, &options.copyright),
+        (Field::Lyrics, &options.lyrics),
+    ] {
+        if let Some(value) = value {
+            update.set(field, value.as_str());
+        }
+    }
+    for (field, values) in [
+        (Field::Artist, &options.artist),
+        (Field::AlbumArtist, &options.album_artist),
+        (Field::Genre, &options.genre),
+        (Field::Composer, &options.composer),
+        (Field::Performer, &options.performer),
+    ] {
+        if !values.is_empty() {
+            update.set_values(field, values.clone());
+        }
+    }
+    for (field, number) in [
+        (Field::TrackNumber, numbering.track),
+        (Field::TrackTotal, numbering.track_total),
+        (Field::DiscNumber, numbering.disc),
+        (Field::DiscTotal, numbering.disc_total),
+    ] {
+        if let Some(number) = number {
+            update.set(field, number.to_string());
+        }
+    }
+    // An inferred title takes precedence over an explicit one, as it always has
+    if options.infer_track {
+        let inferred_title = metadata::infer_track_name_from_filename(path)?;
+        update.set(Field::Title, inferred_title);
+    }
+    if let Some(picture) = cover {
+        // Found covers only go into files that have no pictures yet
+        match options.cover {
+            Some(CoverSource::Auto) => update.fallback_pictures.push(picture.clone()),
+            _ => update.pictures.push(picture.clone()),
+        }
+    }
+
+    Ok(update)
+}
+
+/// Summarise a tag update for the per-file progress output: the values set,
+/// then everything that was removed from the file
+fn describe_update(update: &TagUpdate, before: &Tag, after: &Tag, unmapped: &[String]) -> String {
+    let mut changes: Vec<String> = update.fields.iter()
+        .map(|(field, values)| {
+            let value = values.join("; ").lines().collect::<Vec<_>>().join(" / ");
+            format!("{} = '{}'", field.label().to_lowercase(), value)
+        })
+        .collect();
+    changes.extend(update.custom.iter().map(|(key, value)| format!("{} = '{}'", key, value)));
+    let fallback_pictures = if before.pictures.is_empty() { &update.fallback_pictures[..] } else { &[] };
+    changes.extend(update.pictures.iter().chain(fallback_pictures)
+        .map(|picture| format!("{} picture", picture_type_name(picture.picture_type))));
+
+    let mut removed: Vec<String> = before.fields.keys()
+        .filter(|field| !after.fields.contains_key(field))
+        .map(|field| field.label().to_lowercase())
+        .collect();
+    for (key, _) in &before.custom {
+        if after.custom_values(key).is_empty() && !removed.contains(key) {
+            removed.push(key.clone());
+        }
+    }
+    let pictures = before.pictures.iter()
+        .filter(|picture| !after.pictures.iter().any(|p| p.picture_type == picture.picture_type))
+        .count();
+    match pictures {
+        0 => {}
+        1 => removed.push("1 picture".to_string()),
+        n => removed.push(format!("{} pictures", n)),
+    }
+    removed.extend(unmapped.iter().cloned());
+    if !removed.is_empty() {
+        changes.push(format!("removed {}", removed.join(", ")));
+    }
+    changes.join(", ")
 }
 
-/// Process a directory of FLAC files, converting them to MP3
+/// Process a directory of audio files, converting them to another format.
+/// Up to `jobs` files are converted at once, and each file's outcome is passed to `progress`.
+/// Returns `Error::PartialFailure` if any file failed.
 pub fn process_directory_conversion(
-    dir_path: &PathBuf,
+    dir_path: &Path,
     output_dir: Option<&PathBuf>,
-    bitrate: u32,
-    temp_dir: &PathBuf
+    options: &ConvertOptions,
+    walk: &WalkOptions,
+    jobs: usize,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
 ) -> Result<()> {
-    let mut error_count = 0;
-    
-    for entry in fs::read_dir(dir_path)
-        .with_context(|| format!("Failed to read directory: {}", dir_path.display()))? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if let Some(ext_str) = ext.to_str() {
-                    if ext_str.to_lowercase() == "flac" {
-                        // Determine output path
-                        let output_path = if let Some(dir) = output_dir {
-                            dir.join(path.file_stem().unwrap()).with_extension("mp3")
-                        } else {
-                            path.with_extension("mp3")
-                        };
-
-                        // Convert the file
-                        if let Err(e) = metadata::convert_to_mp3(&path, &output_path, bitrate, temp_dir) {
-                            eprintln!("Error converting {}: {}", path.display(), e);
-                            error_count += 1;
-                        }
-                    }
-                }
-            }
+    let files: Vec<PathBuf> = collect_file_groups(dir_path, &INPUT_EXTENSIONS, walk)?.into_iter().flatten().collect();
+    let mut tally = Tally::new(progress);
+
+    pool::for_each_ordered(&files, jobs, |path| {
+        // Subdirectories are mirrored in the output directory
+        let relative_dir = path.parent().unwrap().strip_prefix(dir_path).unwrap_or(Path::new(""));
+
+        // Determine output path
+        let output_dir = output_dir.map(|dir| dir.join(relative_dir));
+        let output_path = convert::output_path(path, output_dir.as_deref(), options.target);
+        if options.dry_run {
+            return convert::preview_conversion(path, &output_path, options);
         }
-    }
+        if let Some(dir) = &output_dir {
+            fs::create_dir_all(dir).map_err(Error::io(dir))?;
+        }
+        // Convert the file
+        convert::convert_file(path, &output_path, options, backup)?;
+        Ok(format!("Converted {} to {}", path.display(), output_path.display()))
+    }, |path, result| tally.file(path.clone(), result));
 
-    if error_count > 0 {
-        println!("\nCompleted with {} errors. Check the messages above for details.", error_count);
+    tally.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cover_rank() {
+        let rank = |name: &str| cover_rank(Path::new(name));
+        assert_eq!(rank("cover.jpg"), Some(0));
+        assert_eq!(rank("Folder.PNG"), Some(1));
+        assert_eq!(rank("front.jpeg"), Some(2));
+        assert_eq!(rank("Album - Cover.jpg"), Some(COVER_NAMES.len()));
+        assert!(rank("folder.jpg") < rank("Album - Cover.jpg"));
+        assert_eq!(rank("Cover Back.jpg"), None);
+        assert_eq!(rank("booklet.jpg"), None);
+        assert_eq!(rank("cover.txt"), None);
+        assert_eq!(rank("discover.jpg"), None);
+
+        // A directory that cannot be searched only costs its files their cover
+        let mut warnings = Vec::new();
+        let missing = std::env::temp_dir().join(format!("audio-metadata-no-such-dir-{}", std::process::id()));
+        assert_eq!(auto_cover(&missing, &mut |message| warnings.push(message)), None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Could not look for a cover image in"));
     }
 
-    Ok(())
-} 
\ No newline at end of file
+    #[test]
+    fn test_disc_folders() {
+        let number = |name: &str| disc_folder_number(Path::new(name));
+        assert_eq!(number("CD1"), Some(1));
+        assert_eq!(number("Disc 2"), Some(2));
+        assert_eq!(number("disk-02"), Some(2));
+        assert_eq!(number("cd.3"), Some(3));
+        assert_eq!(number("Album/CD 4"), Some(4));
+        assert_eq!(number("Discography"), None);
+        assert_eq!(number("CD1 Bonus"), None);
+
+        let album = std::env::temp_dir().join(format!("audio-metadata-disc-test-{}", std::process::id()));
+        for (dir, tracks) in [("CD1", 2), ("CD2", 3)] {
+            fs::create_dir_all(album.join(dir)).unwrap();
+            for track in 1..=tracks {
+                fs::write(album.join(dir).join(format!("{:02}.mp3", track)), b"").unwrap();
+            }
+        }
+        fs::create_dir_all(album.join("Scans")).unwrap();
+        fs::write(album.join("Bonus.mp3"), b"").unwrap();
+
+        // The disc total comes from the sibling disc folders, and track numbers restart on each disc
+        let walk = WalkOptions { recursive: true, ..WalkOptions::default() };
+        let options = SetOptions { infer_order: true, ..SetOptions::default() };
+        let numbering: Vec<(String, Numbering)> = collect_file_groups(&album, &SUPPORTED_EXTENSIONS, &walk).unwrap()
+            .into_iter()
+            .flat_map(|files| {
+                let numbering = group_numbering(&files, &options);
+                files.into_iter().map(|path| path.strip_prefix(&album).unwrap().display().to_string()).zip(numbering)
+            })
+            .collect();
+        let numbers = |track, track_total, disc: Option<u32>| Numbering {
+            track: Some(track),
+            track_total: Some(track_total),
+            disc,
+            disc_total: disc.map(|_| 2),
+        };
+        assert_eq!(numbering, [
+            ("Bonus.mp3".to_string(), numbers(1, 1, None)),
+            ("CD1/01.mp3".to_string(), numbers(1, 2, Some(1))),
+            ("CD1/02.mp3".to_string(), numbers(2, 2, Some(1))),
+            ("CD2/01.mp3".to_string(), numbers(1, 3, Some(2))),
+            ("CD2/02.mp3".to_string(), numbers(2, 3, Some(2))),
+            ("CD2/03.mp3".to_string(), numbers(3, 3, Some(2))),
+        ]);
+
+        // Numbers given explicitly win over inferred ones
+        let options = SetOptions { infer_order: true, track_total: Some(10), disc_total: Some(3), ..SetOptions::default() };
+        let files = [album.join("CD2/01.mp3"), album.join("CD2/02.mp3")];
+        assert_eq!(group_numbering(&files, &options)[1], Numbering {
+            track: Some(2),
+            track_total: Some(10),
+            disc: Some(2),
+            disc_total: Some(3),
+        });
+
+        fs::remove_dir_all(&album).unwrap();
+    }
+}
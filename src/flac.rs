@@ -0,0 +1,418 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::atomic;
+use crate::error::{Error, ParseError, ParseResult, Result};
+use crate::id3;
+use crate::picture::Picture;
+
+const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const APPLICATION: u8 = 2;
+const SEEKTABLE: u8 = 3;
+const VORBIS_COMMENT: u8 = 4;
+const CUESHEET: u8 = 5;
+const PICTURE: u8 = 6;
+
+/// Padding reserved when the metadata has to grow, so later edits can
+/// usually keep the audio frames at the same offset
+const DEFAULT_PADDING: usize = 4096;
+
+/// Largest payload a metadata block header can describe (24-bit length)
+const MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+
+/// A FLAC metadata block. Vorbis comments and pictures are decoded; every
+/// other block type is carried through byte for byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Block {
+    VorbisComment(VorbisComment),
+    Picture(Picture),
+    Other(u8, Vec<u8>),
+}
+
+/// The contents of a VORBIS_COMMENT block
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VorbisComment {
+    pub vendor: String,
+    /// Field name/value pairs in file order. Names are case-insensitive.
+    pub comments: Vec<(String, String)>,
+}
+
+impl VorbisComment {
+    pub(crate) fn parse(data: &[u8]) -> ParseResult<Self> {
+        let mut reader = LeReader { data, pos: 0 };
+        let vendor = reader.string()?;
+        let count = reader.u32()?;
+        let mut comments = Vec::new();
+        for _ in 0..count {
+            let comment = reader.string()?;
+            // Entries without '=' are invalid; skip rather than reject the whole file
+            if let Some((key, value)) = comment.split_once('=') {
+                comments.push((key.to_string(), value.to_string()));
+            }
+        }
+        Ok(VorbisComment { vendor, comments })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.vendor.as_bytes());
+        out.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            let comment = format!("{}={}", key, value);
+            out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            out.extend_from_slice(comment.as_bytes());
+        }
+        out
+    }
+}
+
+/// The metadata blocks of a FLAC file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlacTag {
+    /// An ID3v2 tag some taggers put before the stream marker, kept as is
+    id3_prefix: Vec<u8>,
+    blocks: Vec<Block>,
+    /// Offset of the first audio frame, i.e. the end of the metadata
+    audio_offset: u64,
+}
+
+impl FlacTag {
+    /// Read every metadata block from a FLAC file
+    pub fn read_from_path(path: &Path) -> Result<FlacTag> {
+        let mut file = File::open(path).map_err(Error::io(path))?;
+        FlacTag::read(&mut file).map_err(ParseError::in_file(path, "FLAC"))
+    }
+
+    fn read(file: &mut File) -> ParseResult<FlacTag> {
+        let truncated = |_| ParseError("file ends inside the metadata".to_string());
+        let mut id3_prefix = vec![0; id3::tag_len(file).map_err(truncated)?.unwrap_or(0)];
+        file.seek(SeekFrom::Start(0)).and_then(|_| file.read_exact(&mut id3_prefix)).map_err(truncated)?;
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != b"fLaC" {
+            return Err(ParseError("not a FLAC file".to_string()));
+        }
+
+        let mut blocks = Vec::new();
+        let mut offset = id3_prefix.len() as u64 + 4;
+        loop {
+            let mut header = [0; 4];
+            file.read_exact(&mut header).map_err(truncated)?;
+            let is_last = header[0] & 0x80 != 0;
+            let block_type = header[0] & 0x7F;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let mut data = vec![0; len];
+            file.read_exact(&mut data)
+                .map_err(|_| ParseError(format!("metadata block {} is truncated", block_type)))?;
+            offset += 4 + len as u64;
+
+            match block_type {
+                PADDING => {}
+                VORBIS_COMMENT => blocks.push(Block::VorbisComment(VorbisComment::parse(&data)?)),
+                PICTURE => blocks.push(Block::Picture(parse_picture(&data)?)),
+                127 => return Err(ParseError("invalid metadata block type".to_string())),
+                _ => blocks.push(Block::Other(block_type, data)),
+            }
+            if is_last {
+                break;
+            }
+        }
+
+        if !matches!(blocks.first(), Some(Block::Other(STREAMINFO, _))) {
+            return Err(ParseError("file does not start with a STREAMINFO block".to_string()));
+        }
+        Ok(FlacTag { id3_prefix, blocks, audio_offset: offset })
+    }
+
+    /// The Vorbis comments, or an empty set if the file has none
+    pub fn comments(&self) -> VorbisComment {
+        self.blocks.iter()
+            .find_map(|block| match block {
+                Block::VorbisComment(comments) => Some(comments.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Mutable access to the Vorbis comments, adding an empty block if needed
+    pub fn comments_mut(&mut self) -> &mut VorbisComment {
+        let position = self.blocks.iter().position(|b| matches!(b, Block::VorbisComment(_)));
+        let index = match position {
+            Some(index) => index,
+            None => {
+                let comments = VorbisComment {
+                    vendor: concat!("audio-metadata ", env!("CARGO_PKG_VERSION")).to_string(),
+                    comments: Vec::new(),
+                };
+                // Conventionally the comment block directly follows STREAMINFO
+                self.blocks.insert(1, Block::VorbisComment(comments));
+                1
+            }
+        };
+        match &mut self.blocks[index] {
+            Block::VorbisComment(comments) => comments,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn pictures(&self) -> Vec<&Picture> {
+        self.blocks.iter()
+            .filter_map(|block| match block {
+                Block::Picture(picture) => Some(picture),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The blocks `Tag` has no place for, such as APPLICATION and CUESHEET
+    /// blocks, and any leading ID3v2 tag, by name and rendered for display.
+    /// STREAMINFO and SEEKTABLE describe the audio itself and are left out.
+    pub fn unmapped_blocks(&self) -> Vec<(String, String)> {
+        let id3 = (!self.id3_prefix.is_empty()).then(|| ("ID3v2".to_string(), format!("<{} bytes>", self.id3_prefix.len())));
+        let blocks = self.blocks.iter().filter_map(|block| match block {
+            Block::Other(STREAMINFO | SEEKTABLE, _) => None,
+            Block::Other(APPLICATION, data) if data.len() >= 4 => Some((
+                "APPLICATION".to_string(),
+                format!("{}: <{} bytes>", String::from_utf8_lossy(&data[..4]), data.len() - 4),
+            )),
+            Block::Other(CUESHEET, data) => Some(("CUESHEET".to_string(), format!("<{} bytes>", data.len()))),
+            Block::Other(block_type, data) => Some((format!("BLOCK {}", block_type), format!("<{} bytes>", data.len()))),
+            _ => None,
+        });
+        id3.into_iter().chain(blocks).collect()
+    }
+
+    /// Remove the blocks (and ID3v2 tag) listed by `unmapped_blocks`
+    pub fn remove_unmapped_blocks(&mut self) {
+        self.id3_prefix.clear();
+        self.blocks.retain(|block| match block {
+            Block::Other(block_type, _) => [STREAMINFO, SEEKTABLE].contains(block_type),
+            _ => true,
+        });
+    }
+
+    /// Replace every PICTURE block with the given pictures
+    pub fn set_pictures(&mut self, pictures: Vec<Picture>) {
+        self.blocks.retain(|block| !matches!(block, Block::Picture(_)));
+        self.blocks.extend(pictures.into_iter().map(Block::Picture));
+    }
+
+    /// Write the metadata back to `path`. When the blocks fit in the space the
+    /// old metadata and padding occupied, the remaining space becomes padding
+    /// so the audio frames keep their offset and the file its length; otherwise
+    /// fresh padding is added. A leading ID3v2 tag is written back unchanged.
+    /// Either way the whole file is copied and replaced atomically, so an
+    /// interrupted write leaves the original intact.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        let blocks = self.block_bytes()?;
+        let available = self.audio_offset as usize - self.id3_prefix.len() - 4;
+        let needed: usize = blocks.iter().map(|(_, data)| 4 + data.len()).sum();
+
+        let mut bytes = self.id3_prefix.clone();
+        if needed == available || needed + 4 <= available {
+            let padding = (needed < available).then(|| available - needed - 4);
+            bytes.extend(serialize(&blocks, padding));
+        } else {
+            bytes.extend(serialize(&blocks, Some(DEFAULT_PADDING)));
+        }
+        atomic::replace(path, &bytes, self.audio_offset)
+    }
+
+    fn block_bytes(&self) -> Result<Vec<(u8, Vec<u8>)>> {
+        self.blocks.iter()
+            .map(|block| {
+                let (block_type, data) = match block {
+                    Block::VorbisComment(comments) => (VORBIS_COMMENT, comments.to_bytes()),
+                    Block::Picture(picture) => (PICTURE, picture_to_bytes(picture)),
+                    Block::Other(block_type, data) => (*block_type, data.clone()),
+                };
+                if data.len() > MAX_BLOCK_LEN {
+                    return Err(Error::InvalidInput(format!(
+                        "FLAC metadata block {} is too large ({} bytes)", block_type, data.len()
+                    )));
+                }
+                Ok((block_type, data))
+            })
+            .collect()
+    }
+}
+
+/// Read `path`'s metadata, apply `edit`, and write it back in a single pass
+pub fn update(path: &Path, edit: impl FnOnce(&mut FlacTag)) -> Result<()> {
+    let mut tag = FlacTag::read_from_path(path)?;
+    edit(&mut tag);
+    tag.write_to_path(path)
+}
+
+/// Serialize the stream marker and blocks, optionally followed by a padding block
+fn serialize(blocks: &[(u8, Vec<u8>)], padding: Option<usize>) -> Vec<u8> {
+    let mut out = b"fLaC".to_vec();
+    let count = blocks.len() + padding.is_some() as usize;
+    let all = blocks.iter()
+        .map(|(block_type, data)| (*block_type, data.len()))
+        .chain(padding.map(|len| (PADDING, len)));
+    for (index, (block_type, len)) in all.enumerate() {
+        let last_flag = if index + 1 == count { 0x80 } else { 0 };
+        out.push(last_flag | block_type);
+        out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+        match blocks.get(index) {
+            Some((_, data)) => out.extend_from_slice(data),
+            None => out.resize(out.len() + len, 0),
+        }
+    }
+    out
+}
+
+fn parse_picture(data: &[u8]) -> ParseResult<Picture> {
+    let mut reader = BeReader { data, pos: 0 };
+    let picture_type = reader.u32()?;
+    let mime_type = String::from_utf8_lossy(reader.bytes()?).into_owned();
+    let description = String::from_utf8_lossy(reader.bytes()?).into_owned();
+    let width = reader.u32()?;
+    let height = reader.u32()?;
+    let color_depth = reader.u32()?;
+    let colors = reader.u32()?;
+    let data = reader.bytes()?.to_vec();
+    Ok(Picture { picture_type, mime_type, description, width, height, color_depth, colors, data })
+}
+
+/// Serialize a picture as the body of a FLAC PICTURE block, which is also the
+/// payload of an Ogg METADATA_BLOCK_PICTURE comment
+pub(crate) fn picture_to_bytes(picture: &Picture) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&picture.picture_type.to_be_bytes());
+    for field in [picture.mime_type.as_bytes(), picture.description.as_bytes()] {
+        out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        out.extend_from_slice(field);
+    }
+    for value in [picture.width, picture.height, picture.color_depth, picture.colors] {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+    out.extend_from_slice(&(picture.data.len() as u32).to_be_bytes());
+    out.extend_from_slice(&picture.data);
+    out
+}
+
+/// Reader for the little-endian, length-prefixed strings of a Vorbis comment block
+struct LeReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl LeReader<'_> {
+    fn u32(&mut self) -> ParseResult<u32> {
+        let bytes = self.data.get(self.pos..self.pos + 4)
+            .ok_or_else(|| ParseError("Vorbis comment block is truncated".to_string()))?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> ParseResult<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| ParseError("Vorbis comment block is truncated".to_string()))?;
+        self.pos += len;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Reader for the big-endian fields of a PICTURE block
+struct BeReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BeReader<'a> {
+    fn u32(&mut self) -> ParseResult<u32> {
+        let bytes = self.data.get(self.pos..self.pos + 4)
+            .ok_or_else(|| ParseError("picture block is truncated".to_string()))?;
+        self.pos += 4;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> ParseResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| ParseError("picture block is truncated".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn minimal_flac(padding: usize) -> Vec<u8> {
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend_from_slice(&[STREAMINFO, 0, 0, 34]);
+        bytes.extend_from_slice(&[0; 34]);
+        bytes.push(0x80 | PADDING);
+        bytes.extend_from_slice(&(padding as u32).to_be_bytes()[1..]);
+        bytes.resize(bytes.len() + padding, 0);
+        bytes.extend_from_slice(b"\xFF\xF8audio");
+        bytes
+    }
+
+    #[test]
+    fn test_update_comments_and_picture() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-flac-{}.flac", std::process::id()));
+        fs::write(&path, minimal_flac(16)).unwrap();
+
+        // Too big for the existing padding: the file grows
+        update(&path, |tag| {
+            let comments = &mut tag.comments_mut().comments;
+            comments.push(("ARTIST".to_string(), "Artist".to_string()));
+            comments.push(("TITLE".to_string(), "Title".to_string()));
+            tag.set_pictures(vec![Picture {
+                picture_type: Picture::FRONT_COVER,
+                mime_type: "image/png".to_string(),
+                description: String::new(),
+                width: 1,
+                height: 1,
+                color_depth: 24,
+                colors: 0,
+                data: vec![1, 2, 3],
+            }]);
+        }).unwrap();
+        let grown_len = fs::metadata(&path).unwrap().len();
+
+        // Fits in the new padding: the audio keeps its offset and the file its length
+        update(&path, |tag| tag.comments_mut().comments[0].1 = "Another Artist".to_string()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let tag = FlacTag::read_from_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len() as u64, grown_len);
+        assert!(bytes.ends_with(b"\xFF\xF8audio"));
+        assert_eq!(tag.comments().comments, vec![
+            ("ARTIST".to_string(), "Another Artist".to_string()),
+            ("TITLE".to_string(), "Title".to_string()),
+        ]);
+        assert_eq!(tag.pictures().len(), 1);
+        assert_eq!(tag.pictures()[0].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_leading_id3_tag() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-flac-id3-{}.flac", std::process::id()));
+        let mut bytes = b"ID3\x03\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00".to_vec();
+        bytes.extend(minimal_flac(64));
+        fs::write(&path, &bytes).unwrap();
+
+        update(&path, |tag| tag.comments_mut().comments.push(("TITLE".to_string(), "Title".to_string()))).unwrap();
+        let written = fs::read(&path).unwrap();
+        let tag = FlacTag::read_from_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(written.starts_with(b"ID3\x03\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00fLaC"));
+        assert_eq!(written.len(), bytes.len());
+        assert!(written.ends_with(b"\xFF\xF8audio"));
+        assert_eq!(tag.comments().comments, vec![("TITLE".to_string(), "Title".to_string())]);
+        assert_eq!(tag.unmapped_blocks(), vec![("ID3v2".to_string(), "<14 bytes>".to_string())]);
+    }
+}
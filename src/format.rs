@@ -0,0 +1,377 @@
+use crate::error::{Error, Result};
+use std::path::Path;
+
+use crate::flac::{self, FlacTag};
+use crate::id3::{self, Frame, Id3Tag, genre_names};
+use crate::tag::{Field, Id3Frame, Tag, id3_description, key_for_id3_description, parse_tag_key};
+
+/// Container-level details that the format-agnostic `Tag` does not carry
+#[derive(Debug, Clone)]
+pub struct NativeInfo {
+    /// Container and tag format, e.g. "MP3 (ID3v2.4)"
+    pub description: String,
+    /// Native frames or comments that `Tag` cannot represent, rendered for
+    /// display. They are left untouched by `write_tag` unless
+    /// `WriteOptions::remove_unmapped` is set.
+    pub unmapped: Vec<(String, String)>,
+}
+
+/// How `AudioFormat::write_tag` writes values that a format cannot store as is
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Joins the values of a multi-valued field in ID3v2.3 tags, which cannot
+    /// hold several values in one frame (default "/", as the ID3v2.3 spec suggests)
+    pub id3v23_separator: String,
+    /// Also remove the native data `Tag` cannot represent (`NativeInfo::unmapped`),
+    /// such as unknown ID3v2 frames, ID3v1 trailers and FLAC APPLICATION blocks,
+    /// instead of preserving it
+    pub remove_unmapped: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions { id3v23_separator: "/".to_string(), remove_unmapped: false }
+    }
+}
+
+/// A container format whose tags can be read and written through `Tag`
+pub trait AudioFormat: Sync {
+    /// Read the file's tag, returning an empty tag if it has none
+    fn read_tag(&self, path: &Path) -> Result<Tag>;
+
+    /// Like `read_tag`, but split the values that `write_tag` joined as
+    /// `options` asked (the ID3v2.3 separator) instead of the default way
+    fn read_tag_with(&self, path: &Path, _options: &WriteOptions) -> Result<Tag> {
+        self.read_tag(path)
+    }
+
+    /// Replace the file's tag with `tag` in a single write. Native data that
+    /// `Tag` cannot represent is preserved.
+    fn write_tag(&self, path: &Path, tag: &Tag, options: &WriteOptions) -> Result<()>;
+
+    /// Describe the native tag of the file
+    fn native_info(&self, path: &Path) -> Result<NativeInfo>;
+}
+
+/// MP3 files tagged with ID3v2
+pub struct Mp3Format;
+
+/// The custom tag key MusicBrainz recording IDs are kept under, which ID3v2
+/// stores in a UFID frame owned by `MUSICBRAINZ_UFID_OWNER` rather than in TXXX
+const MUSICBRAINZ_TRACK_ID: &str = "MUSICBRAINZ_TRACKID";
+const MUSICBRAINZ_UFID_OWNER: &str = "http://musicbrainz.org";
+
+/// The ID3v2.3 frames whose values are split on the separator when read: those
+/// of the fields that commonly have several values. Other frames are joined
+/// when written too, but read back as one value.
+const V23_JOINED_FRAMES: [&str; 4] = ["TPE1", "TPE2", "TCOM", "TCON"];
+
+/// FLAC files tagged with Vorbis comments and PICTURE blocks
+pub struct FlacFormat;
+
+/// Pick the format implementation for a file from its extension
+pub fn format_for_path(path: &Path) -> Result<&'static dyn AudioFormat> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase);
+
+    match extension.as_deref() {
+        Some("flac") => Ok(&FlacFormat),
+        Some("mp3") => Ok(&Mp3Format),
+        _ => Err(Error::UnsupportedFormat { path: path.to_path_buf() }),
+    }
+}
+
+impl AudioFormat for Mp3Format {
+    fn read_tag(&self, path: &Path) -> Result<Tag> {
+        self.read_tag_with(path, &WriteOptions::default())
+    }
+
+    fn read_tag_with(&self, path: &Path, options: &WriteOptions) -> Result<Tag> {
+        let Some(native) = Id3Tag::read_from_path(path)? else {
+            return Ok(Tag::default());
+        };
+        // ID3v2.3 frames hold one value, so several were joined with the separator
+        let separator = options.id3v23_separator.as_str();
+        let text_values = |id| {
+            let values = native.text_values(id);
+            if native.version != 3 || separator.is_empty() || !V23_JOINED_FRAMES.contains(&id) {
+                return values;
+            }
+            values.iter()
+                .flat_map(|value| value.split(separator))
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        let mut tag = Tag::default();
+        // Totals are read from the "n/N" frames of their numbers
+        for field in Field::ALL.into_iter().filter(|field| !field.is_total()) {
+            let values = match field.id3_frame(native.version) {
+                Id3Frame::Text("TYER") => native.v23_date().into_iter().collect(),
+                Id3Frame::Text("TCON") => text_values("TCON").iter().flat_map(|value| genre_names(value)).collect(),
+                Id3Frame::Text(id) => text_values(id),
+                Id3Frame::Described(id) => native.described_values(id, ""),
+                Id3Frame::UserText(description) => native.user_text_values(description),
+            };
+            if !values.is_empty() {
+                tag.fields.insert(field, values);
+            }
+        }
+        tag.split_numbers();
+        tag.custom = native.frames.iter()
+            .filter_map(Frame::user_text_value)
+            .filter(|(description, _)| user_text_field(description, native.version).is_none())
+            .map(|(description, value)| (key_for_id3_description(&description).to_string(), value))
+            .collect();
+        tag.custom.extend(native.frames.iter()
+            .filter_map(Frame::ufid_value)
+            .filter(|(owner, _)| owner == MUSICBRAINZ_UFID_OWNER)
+            .map(|(_, identifier)| (MUSICBRAINZ_TRACK_ID.to_string(), identifier)));
+        tag.pictures = native.frames.iter().filter_map(Frame::picture).collect();
+        Ok(tag)
+    }
+
+    fn write_tag(&self, path: &Path, tag: &Tag, options: &WriteOptions) -> Result<()> {
+        let edit = |native: &mut Id3Tag| {
+            let version = native.version;
+            native.v23_separator = options.id3v23_separator.clone();
+            if options.remove_unmapped {
+                native.frames.retain(|frame| is_mapped(frame, version));
+            }
+            native.remove("TXXX");
+            native.remove("APIC");
+            native.frames.retain(|frame| frame.ufid_value().is_none_or(|(owner, _)| owner != MUSICBRAINZ_UFID_OWNER));
+            for field in Field::ALL.into_iter().filter(|field| !field.is_total()) {
+                let values = tag.joined_number(field).unwrap_or_default();
+                match field.id3_frame(version) {
+                    Id3Frame::Text("TYER") => native.set_v23_date(values.first().map(String::as_str)),
+                    Id3Frame::Text(id) if values.is_empty() => native.remove(id),
+                    Id3Frame::Text(id) => native.replace(Frame::text_list(id, &values, version, &options.id3v23_separator)),
+                    Id3Frame::Described(id) => {
+                        // Comments and lyrics with a description belong to other applications
+                        native.frames.retain(|frame| {
+                            frame.id != id || frame.described_value().is_some_and(|(description, _)| !description.is_empty())
+                        });
+                        for value in &values {
+                            native.frames.push(Frame::described_text(id, "", value, version));
+                        }
+                    }
+                    Id3Frame::UserText(description) => {
+                        for value in &values {
+                            native.frames.push(Frame::user_text(description, value, version));
+                        }
+                    }
+                }
+            }
+            for (key, value) in &tag.custom {
+                if key.eq_ignore_ascii_case(MUSICBRAINZ_TRACK_ID) {
+                    native.frames.push(Frame::ufid(MUSICBRAINZ_UFID_OWNER, value));
+                } else {
+                    native.frames.push(Frame::user_text(id3_description(key), value, version));
+                }
+            }
+            for picture in &tag.pictures {
+                native.frames.push(Frame::from_picture(picture, version));
+            }
+        };
+        if options.remove_unmapped {
+            id3::update_stripped(path, edit)
+        } else {
+            id3::update(path, edit)
+        }
+    }
+
+    fn native_info(&self, path: &Path) -> Result<NativeInfo> {
+        let v1 = id3::read_v1_summary(path)?.map(|summary| ("ID3v1".to_string(), summary));
+        let Some(native) = Id3Tag::read_from_path(path)? else {
+            return Ok(NativeInfo { description: "MP3 (no ID3v2 tag)".to_string(), unmapped: v1.into_iter().collect() });
+        };
+        Ok(NativeInfo {
+            description: format!("MP3 (ID3v2.{})", native.version),
+            unmapped: native.frames.iter()
+                .filter(|frame| !is_mapped(frame, native.version))
+                .map(|frame| (frame.id.clone(), frame.display_value()))
+                .chain(v1)
+                .collect(),
+        })
+    }
+}
+
+/// Whether an ID3v2 frame is represented in `Tag`
+fn is_mapped(frame: &Frame, version: u8) -> bool {
+    match frame.id.as_str() {
+        "TXXX" | "APIC" => true,
+        // The day, month and time of an ID3v2.3 date
+        "TDAT" | "TIME" => version == 3,
+        "UFID" => frame.ufid_value().is_some_and(|(owner, _)| owner == MUSICBRAINZ_UFID_OWNER),
+        "COMM" | "USLT" => frame.described_value().is_some_and(|(description, _)| description.is_empty()),
+        id => Field::ALL.iter().any(|f| matches!(f.id3_frame(version), Id3Frame::Text(t) if t == id)),
+    }
+}
+
+impl AudioFormat for FlacFormat {
+    fn read_tag(&self, path: &Path) -> Result<Tag> {
+        let native = FlacTag::read_from_path(path)?;
+        let mut tag = Tag::default();
+        for (key, value) in native.comments().comments.into_iter().filter(|(key, _)| is_mapped_comment(key)) {
+            match Field::from_vorbis_key(&key) {
+                Some(field) => tag.fields.entry(field).or_default().push(value),
+                None => tag.custom.push((key, value)),
+            }
+        }
+        tag.split_numbers();
+        tag.pictures = native.pictures().into_iter().cloned().collect();
+        Ok(tag)
+    }
+
+    fn write_tag(&self, path: &Path, tag: &Tag, options: &WriteOptions) -> Result<()> {
+        flac::update(path, |native| {
+            if options.remove_unmapped {
+                native.remove_unmapped_blocks();
+            }
+            // The Vorbis comments `Tag` represents map one-to-one onto it, so they are rebuilt from scratch
+            let comments = &mut native.comments_mut().comments;
+            comments.retain(|(key, _)| !options.remove_unmapped && !is_mapped_comment(key));
+            for (field, values) in &tag.fields {
+                for value in values {
+                    comments.push((field.vorbis_key().to_string(), value.clone()));
+                }
+            }
+            comments.extend(tag.custom.iter().cloned());
+            native.set_pictures(tag.pictures.clone());
+        })
+    }
+
+    fn native_info(&self, path: &Path) -> Result<NativeInfo> {
+        let native = FlacTag::read_from_path(path)?;
+        let comments = native.comments().comments.into_iter()
+            .filter(|(key, _)| !is_mapped_comment(key))
+            .map(|(key, value)| if key.eq_ignore_ascii_case(PICTURE_COMMENT) {
+                (key, format!("<{} bytes of base64>", value.len()))
+            } else {
+                (key, value)
+            });
+        Ok(NativeInfo {
+            description: "FLAC (Vorbis comments)".to_string(),
+            unmapped: native.unmapped_blocks().into_iter().chain(comments).collect(),
+        })
+    }
+}
+
+/// The Ogg way of embedding a picture, which FLAC files should store in a PICTURE block instead
+const PICTURE_COMMENT: &str = "METADATA_BLOCK_PICTURE";
+
+/// Whether a Vorbis comment is represented in `Tag`: pictures embedded as
+/// comments are not, and neither are comments whose key is not a valid field
+/// name, which could not be given as a custom tag
+fn is_mapped_comment(key: &str) -> bool {
+    parse_tag_key(key).is_ok() && !key.eq_ignore_ascii_case(PICTURE_COMMENT)
+}
+
+/// The field stored in TXXX frames with this description, if any
+fn user_text_field(description: &str, version: u8) -> Option<Field> {
+    Field::ALL.into_iter().find(|field| {
+        matches!(field.id3_frame(version), Id3Frame::UserText(d) if d.eq_ignore_ascii_case(description))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picture::Picture;
+    use std::fs;
+
+    #[test]
+    fn test_tag_round_trip() {
+        let flac = {
+            let mut bytes = b"fLaC\x80\x00\x00\x22".to_vec();
+            bytes.extend_from_slice(&[0; 34]);
+            bytes
+        };
+        let mut tag = Tag::default();
+        tag.fields.insert(Field::Artist, vec!["A".to_string(), "B".to_string()]);
+        tag.fields.insert(Field::TrackNumber, vec!["3".to_string()]);
+        tag.fields.insert(Field::TrackTotal, vec!["12".to_string()]);
+        tag.fields.insert(Field::DiscNumber, vec!["2".to_string()]);
+        tag.fields.insert(Field::DiscTotal, vec!["2".to_string()]);
+        tag.fields.insert(Field::Genre, vec!["Rock".to_string()]);
+        tag.fields.insert(Field::Performer, vec!["P".to_string()]);
+        tag.fields.insert(Field::Comment, vec!["Nice".to_string()]);
+        tag.fields.insert(Field::Lyrics, vec!["Line one\nLine two".to_string()]);
+        tag.custom.push(("CATALOGNUMBER".to_string(), "XYZ-1".to_string()));
+        tag.custom.push(("MUSICBRAINZ_ALBUMID".to_string(), "1d4f7a8b".to_string()));
+        tag.custom.push(("MUSICBRAINZ_TRACKID".to_string(), "9c3e2f10".to_string()));
+        tag.set_picture(Picture {
+            picture_type: Picture::FRONT_COVER,
+            mime_type: "image/jpeg".to_string(),
+            description: String::new(),
+            width: 0,
+            height: 0,
+            color_depth: 0,
+            colors: 0,
+            data: vec![0xFF, 0xD8, 0xFF, 0xD9],
+        });
+
+        for (extension, contents) in [("mp3", b"\xFF\xFB".to_vec()), ("flac", flac)] {
+            let path = std::env::temp_dir()
+                .join(format!("audio-metadata-format-{}.{}", std::process::id(), extension));
+            fs::write(&path, contents).unwrap();
+            let format = format_for_path(&path).unwrap();
+            format.write_tag(&path, &tag, &WriteOptions::default()).unwrap();
+            let read = format.read_tag(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(read, tag, "round trip failed for {}", extension);
+        }
+    }
+
+    #[test]
+    fn test_v23_date() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-format-v23-{}.mp3", std::process::id()));
+        fs::write(&path, b"\xFF\xFB").unwrap();
+        let mut native = Id3Tag::new(3);
+        native.replace(Frame::text("TIT2", "Song", 3));
+        native.write_to_path(&path).unwrap();
+
+        let mut tag = Mp3Format.read_tag(&path).unwrap();
+        tag.fields.insert(Field::Date, vec!["1999-03-24".to_string()]);
+        Mp3Format.write_tag(&path, &tag, &WriteOptions::default()).unwrap();
+        let native = Id3Tag::read_from_path(&path).unwrap().unwrap();
+        let read = Mp3Format.read_tag(&path).unwrap();
+        let unmapped = Mp3Format.native_info(&path).unwrap().unmapped;
+        fs::remove_file(&path).unwrap();
+
+        // TYER is always four characters, so the day and month go in TDAT
+        assert_eq!(native.version, 3);
+        assert_eq!(native.text_values("TYER"), vec!["1999"]);
+        assert_eq!(native.text_values("TDAT"), vec!["2403"]);
+        assert_eq!(read.fields[&Field::Date], vec!["1999-03-24"]);
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn test_v23_multiple_values() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-format-v23-values-{}.mp3", std::process::id()));
+        fs::write(&path, b"\xFF\xFB").unwrap();
+        Id3Tag::new(3).write_to_path(&path).unwrap();
+
+        let mut tag = Tag::default();
+        tag.fields.insert(Field::Artist, vec!["A".to_string(), "B".to_string()]);
+        tag.fields.insert(Field::Title, vec!["AC/DC".to_string()]);
+        let mut read = Vec::new();
+        for separator in ["/", "; "] {
+            let options = WriteOptions { id3v23_separator: separator.to_string(), ..WriteOptions::default() };
+            Mp3Format.write_tag(&path, &tag, &options).unwrap();
+            read.push(Mp3Format.read_tag_with(&path, &options).unwrap());
+        }
+        let native = Id3Tag::read_from_path(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Artists are joined in one frame and split again; titles are never split
+        assert_eq!(native.text_values("TPE1"), vec!["A; B"]);
+        for read in read {
+            assert_eq!(read, tag);
+        }
+    }
+}
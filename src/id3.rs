@@ -0,0 +1,1076 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::atomic;
+use crate::error::{Error, ParseError, ParseResult, Result};
+use crate::picture::{Picture, image_info};
+
+/// Size of the fixed ID3v2 tag header (and of the optional v2.4 footer)
+const HEADER_LEN: usize = 10;
+
+/// Padding reserved after the frames whenever a tag has to grow, so later
+/// edits can usually keep the audio data at the same offset
+const DEFAULT_PADDING: usize = 1024;
+
+/// Text encodings defined by ID3v2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Latin1,
+    Utf16,
+    Utf16Be,
+    Utf8,
+}
+
+impl TextEncoding {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TextEncoding::Latin1),
+            1 => Some(TextEncoding::Utf16),
+            2 => Some(TextEncoding::Utf16Be),
+            3 => Some(TextEncoding::Utf8),
+            _ => None,
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            TextEncoding::Latin1 => 0,
+            TextEncoding::Utf16 => 1,
+            TextEncoding::Utf16Be => 2,
+            TextEncoding::Utf8 => 3,
+        }
+    }
+
+    fn terminator(self) -> &'static [u8] {
+        match self {
+            TextEncoding::Latin1 | TextEncoding::Utf8 => &[0],
+            TextEncoding::Utf16 | TextEncoding::Utf16Be => &[0, 0],
+        }
+    }
+
+    /// Pick the encoding used when writing `text` into a tag of the given major version.
+    /// v2.4 always gets UTF-8; v2.3 gets Latin-1 when possible and UTF-16 otherwise.
+    fn preferred(version: u8, text: &str) -> Self {
+        if version >= 4 {
+            TextEncoding::Utf8
+        } else if text.chars().all(|c| (c as u32) <= 0xFF) {
+            TextEncoding::Latin1
+        } else {
+            TextEncoding::Utf16
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            TextEncoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            TextEncoding::Utf16 => match bytes {
+                [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes),
+                [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+                // A missing BOM is technically invalid; big-endian is the spec default
+                _ => decode_utf16(bytes, u16::from_be_bytes),
+            },
+            TextEncoding::Utf16Be => decode_utf16(bytes, u16::from_be_bytes),
+        }
+    }
+
+    fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Latin1 => text
+                .chars()
+                .map(|c| if (c as u32) <= 0xFF { c as u8 } else { b'?' })
+                .collect(),
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16 => {
+                let mut out = vec![0xFF, 0xFE];
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&unit.to_le_bytes());
+                }
+                out
+            }
+            TextEncoding::Utf16Be => text
+                .encode_utf16()
+                .flat_map(|unit| unit.to_be_bytes())
+                .collect(),
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| to_unit([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Split `data` at the first string terminator for `encoding`, returning the
+/// string bytes and whatever follows the terminator
+fn split_terminated(data: &[u8], encoding: TextEncoding) -> (&[u8], &[u8]) {
+    match encoding {
+        TextEncoding::Latin1 | TextEncoding::Utf8 => match data.iter().position(|&b| b == 0) {
+            Some(pos) => (&data[..pos], &data[pos + 1..]),
+            None => (data, &[]),
+        },
+        TextEncoding::Utf16 | TextEncoding::Utf16Be => {
+            let mut pos = 0;
+            while pos + 1 < data.len() {
+                if data[pos] == 0 && data[pos + 1] == 0 {
+                    return (&data[..pos], &data[pos + 2..]);
+                }
+                pos += 2;
+            }
+            (data, &[])
+        }
+    }
+}
+
+/// A single ID3v2 frame with its payload already stripped of unsynchronisation,
+/// grouping bytes and data length indicators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: String,
+    pub data: Vec<u8>,
+    /// Major version and raw flags of a compressed or encrypted frame. Such frames
+    /// are carried through untouched, but only when writing the same version.
+    opaque: Option<(u8, u16)>,
+}
+
+impl Frame {
+    pub fn new(id: &str, data: Vec<u8>) -> Self {
+        Frame { id: id.to_string(), data, opaque: None }
+    }
+
+    /// Build a text frame (T***, except TXXX) holding a single value
+    pub fn text(id: &str, value: &str, version: u8) -> Self {
+        Frame::text_list(id, &[value.to_string()], version, "/")
+    }
+
+    /// Build a text frame holding several values. ID3v2.4 separates them with
+    /// the encoding's terminator; v2.3 has no multi-value support, so they are
+    /// joined with `v23_separator` instead.
+    pub fn text_list(id: &str, values: &[String], version: u8, v23_separator: &str) -> Self {
+        let joined;
+        let values = if version >= 4 || values.len() <= 1 {
+            values
+        } else {
+            joined = [values.join(v23_separator)];
+            &joined[..]
+        };
+        let encoding = TextEncoding::preferred(version, &values.concat());
+        let mut data = vec![encoding.byte()];
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                data.extend_from_slice(encoding.terminator());
+            }
+            data.extend(encoding.encode(value));
+        }
+        Frame::new(id, data)
+    }
+
+    /// Build a TXXX (user-defined text) frame
+    pub fn user_text(description: &str, value: &str, version: u8) -> Self {
+        let encoding = TextEncoding::preferred(version, &format!("{}{}", description, value));
+        let mut data = vec![encoding.byte()];
+        data.extend(encoding.encode(description));
+        data.extend_from_slice(encoding.terminator());
+        data.extend(encoding.encode(value));
+        Frame::new("TXXX", data)
+    }
+
+    /// Build a COMM or USLT frame: an English-language text with a description
+    pub fn described_text(id: &str, description: &str, text: &str, version: u8) -> Self {
+        let encoding = TextEncoding::preferred(version, &format!("{}{}", description, text));
+        let mut data = vec![encoding.byte()];
+        data.extend_from_slice(b"eng");
+        data.extend(encoding.encode(description));
+        data.extend_from_slice(encoding.terminator());
+        data.extend(encoding.encode(text));
+        Frame::new(id, data)
+    }
+
+    /// Build a UFID (unique file identifier) frame
+    pub fn ufid(owner: &str, identifier: &str) -> Self {
+        let mut data = TextEncoding::Latin1.encode(owner);
+        data.push(0);
+        data.extend_from_slice(identifier.as_bytes());
+        Frame::new("UFID", data)
+    }
+
+    /// Build an APIC frame from a picture
+    pub fn from_picture(picture: &Picture, version: u8) -> Self {
+        let encoding = TextEncoding::preferred(version, &picture.description);
+        let mut data = vec![encoding.byte()];
+        data.extend(TextEncoding::Latin1.encode(&picture.mime_type));
+        data.push(0);
+        data.push(picture.picture_type as u8);
+        data.extend(encoding.encode(&picture.description));
+        data.extend_from_slice(encoding.terminator());
+        data.extend_from_slice(&picture.data);
+        Frame::new("APIC", data)
+    }
+
+    /// Decode a TXXX frame into its description and value
+    pub fn user_text_value(&self) -> Option<(String, String)> {
+        if self.id != "TXXX" || self.opaque.is_some() {
+            return None;
+        }
+        let (&encoding_byte, rest) = self.data.split_first()?;
+        let encoding = TextEncoding::from_byte(encoding_byte)?;
+        let (description, value) = split_terminated(rest, encoding);
+        let (value, _) = split_terminated(value, encoding);
+        Some((encoding.decode(description), encoding.decode(value)))
+    }
+
+    /// Decode a UFID frame into its owner and identifier
+    pub fn ufid_value(&self) -> Option<(String, String)> {
+        if self.id != "UFID" || self.opaque.is_some() {
+            return None;
+        }
+        let (owner, identifier) = split_terminated(&self.data, TextEncoding::Latin1);
+        Some((TextEncoding::Latin1.decode(owner), String::from_utf8_lossy(identifier).into_owned()))
+    }
+
+    /// Decode a COMM or USLT frame into its description and text, skipping
+    /// the language code
+    pub fn described_value(&self) -> Option<(String, String)> {
+        if !matches!(self.id.as_str(), "COMM" | "USLT") || self.opaque.is_some() {
+            return None;
+        }
+        let (&encoding_byte, rest) = self.data.split_first()?;
+        let encoding = TextEncoding::from_byte(encoding_byte)?;
+        let (description, text) = split_terminated(rest.get(3..)?, encoding);
+        let (text, _) = split_terminated(text, encoding);
+        Some((encoding.decode(description), encoding.decode(text)))
+    }
+
+    /// Decode the values of a text frame. ID3v2.4 allows several values separated
+    /// by the encoding's terminator; earlier versions hold exactly one.
+    pub fn text_values(&self) -> Option<Vec<String>> {
+        if !self.id.starts_with('T') || self.id == "TXXX" || self.opaque.is_some() {
+            return None;
+        }
+        let (&encoding_byte, mut rest) = self.data.split_first()?;
+        let encoding = TextEncoding::from_byte(encoding_byte)?;
+        let mut values = Vec::new();
+        while !rest.is_empty() {
+            let (value, remainder) = split_terminated(rest, encoding);
+            values.push(encoding.decode(value));
+            rest = remainder;
+        }
+        // A trailing terminator does not introduce an extra empty value
+        if values.is_empty() {
+            values.push(String::new());
+        }
+        Some(values)
+    }
+
+    /// Decode an APIC frame into a picture
+    pub fn picture(&self) -> Option<Picture> {
+        if self.id != "APIC" || self.opaque.is_some() {
+            return None;
+        }
+        let (&encoding_byte, rest) = self.data.split_first()?;
+        let encoding = TextEncoding::from_byte(encoding_byte)?;
+        let (mime_type, rest) = split_terminated(rest, TextEncoding::Latin1);
+        let (&picture_type, rest) = rest.split_first()?;
+        let (description, data) = split_terminated(rest, encoding);
+        let info = image_info(data);
+        Some(Picture {
+            picture_type: picture_type as u32,
+            mime_type: TextEncoding::Latin1.decode(mime_type),
+            description: encoding.decode(description),
+            width: info.map_or(0, |i| i.width),
+            height: info.map_or(0, |i| i.height),
+            color_depth: info.map_or(0, |i| i.color_depth),
+            colors: info.map_or(0, |i| i.colors),
+            data: data.to_vec(),
+        })
+    }
+
+    /// A human-readable rendering of the frame's contents
+    pub fn display_value(&self) -> String {
+        if let Some(values) = self.text_values() {
+            return values.join("; ");
+        }
+        let value = match self.id.as_str() {
+            _ if self.opaque.is_some() => None,
+            "TXXX" => self.user_text_value()
+                .map(|(description, value)| format!("{}: {}", description, value)),
+            "COMM" | "USLT" => self.described_value().map(|(description, value)| {
+                if description.is_empty() { value } else { format!("{}: {}", description, value) }
+            }),
+            id if id.starts_with('W') && id != "WXXX" => {
+                Some(TextEncoding::Latin1.decode(split_terminated(&self.data, TextEncoding::Latin1).0))
+            }
+            _ => None,
+        };
+        value.unwrap_or_else(|| format!("<{} bytes>", self.data.len()))
+    }
+}
+
+/// An ID3v2 tag: a major version and its frames in file order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Id3Tag {
+    /// Major version (3 or 4). ID3v2.2 tags are upgraded to 3 when read,
+    /// since their three-character frame layout cannot be written back.
+    pub version: u8,
+    pub frames: Vec<Frame>,
+    /// Joins the values of a UTF-8 multi-value text frame when it has to be
+    /// written to an ID3v2.3 tag, which holds one value per frame (default "/")
+    pub v23_separator: String,
+}
+
+impl Default for Id3Tag {
+    fn default() -> Self {
+        Id3Tag::new(4)
+    }
+}
+
+impl Id3Tag {
+    pub fn new(version: u8) -> Self {
+        Id3Tag { version, frames: Vec::new(), v23_separator: "/".to_string() }
+    }
+
+    /// Read the ID3v2 tag at the start of a file, if there is one
+    pub fn read_from_path(path: &Path) -> Result<Option<Id3Tag>> {
+        let mut file = File::open(path).map_err(Error::io(path))?;
+        let Some(total_len) = tag_len(&mut file).map_err(Error::io(path))? else {
+            return Ok(None);
+        };
+        let mut bytes = vec![0; total_len];
+        file.seek(SeekFrom::Start(0)).map_err(Error::io(path))?;
+        file.read_exact(&mut bytes)
+            .map_err(|_| ParseError("ID3 tag is truncated".to_string()))
+            .and_then(|_| Id3Tag::parse(&bytes))
+            .map(Some)
+            .map_err(ParseError::in_file(path, "ID3v2"))
+    }
+
+    /// Parse a complete tag, starting with its 10-byte header
+    pub fn parse(bytes: &[u8]) -> ParseResult<Id3Tag> {
+        if bytes.len() < HEADER_LEN || &bytes[0..3] != b"ID3" {
+            return Err(ParseError("Missing ID3v2 header".to_string()));
+        }
+        let version = bytes[3];
+        let flags = bytes[5];
+        let size = syncsafe_to_u32(&bytes[6..10]) as usize;
+        if bytes.len() < HEADER_LEN + size {
+            return Err(ParseError("ID3 tag is truncated".to_string()));
+        }
+        let mut body = bytes[HEADER_LEN..HEADER_LEN + size].to_vec();
+        let tag_unsync = flags & 0x80 != 0;
+
+        match version {
+            2 => {
+                if flags & 0x40 != 0 {
+                    return Err(ParseError("Compressed ID3v2.2 tags are not supported".to_string()));
+                }
+                if tag_unsync {
+                    body = remove_unsync(&body);
+                }
+                Ok(Id3Tag { frames: parse_v22_frames(&body)?, ..Id3Tag::new(3) })
+            }
+            3 => {
+                if tag_unsync {
+                    body = remove_unsync(&body);
+                }
+                let mut start = 0;
+                if flags & 0x40 != 0 {
+                    let ext_len = read_u32_be(&body, 0)? as usize;
+                    start = 4 + ext_len;
+                }
+                Ok(Id3Tag { frames: parse_frames(&body[start.min(body.len())..], 3, false)?, ..Id3Tag::new(3) })
+            }
+            4 => {
+                let mut start = 0;
+                if flags & 0x40 != 0 {
+                    if body.len() < 4 {
+                        return Err(ParseError("ID3 extended header is truncated".to_string()));
+                    }
+                    start = syncsafe_to_u32(&body[0..4]) as usize;
+                }
+                Ok(Id3Tag { frames: parse_frames(&body[start.min(body.len())..], 4, tag_unsync)?, ..Id3Tag::new(4) })
+            }
+            _ => Err(ParseError(format!("Unsupported ID3v2 version: 2.{}", version))),
+        }
+    }
+
+    /// Every value of a text frame (and of any duplicate frames with the same ID)
+    pub fn text_values(&self, id: &str) -> Vec<String> {
+        self.frames.iter()
+            .filter(|frame| frame.id == id)
+            .filter_map(Frame::text_values)
+            .flatten()
+            .collect()
+    }
+
+    /// The texts of the COMM or USLT frames with the given description
+    pub fn described_values(&self, id: &str, description: &str) -> Vec<String> {
+        self.frames.iter()
+            .filter(|frame| frame.id == id)
+            .filter_map(Frame::described_value)
+            .filter(|(d, _)| d == description)
+            .map(|(_, text)| text)
+            .collect()
+    }
+
+    /// The values of the TXXX frames with the given description (compared
+    /// case-insensitively, as taggers disagree on case)
+    pub fn user_text_values(&self, description: &str) -> Vec<String> {
+        self.frames.iter()
+            .filter_map(Frame::user_text_value)
+            .filter(|(d, _)| d.eq_ignore_ascii_case(description))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// The date held by the ID3v2.3 TYER, TDAT ("DDMM") and TIME ("HHMM")
+    /// frames as an ISO date, e.g. "1999-03-24T12:30", or just the year if
+    /// there is no TDAT frame
+    pub fn v23_date(&self) -> Option<String> {
+        let year = self.text_values("TYER").into_iter().next()?;
+        let four_digits = |id| self.text_values(id).into_iter().next()
+            .filter(|text| text.len() == 4 && text.bytes().all(|b| b.is_ascii_digit()));
+        Some(match (four_digits("TDAT"), four_digits("TIME")) {
+            (Some(date), Some(time)) => format!("{}-{}-{}T{}:{}", year, &date[2..4], &date[0..2], &time[0..2], &time[2..4]),
+            (Some(date), None) => format!("{}-{}-{}", year, &date[2..4], &date[0..2]),
+            _ => year,
+        })
+    }
+
+    /// Store a date such as "1999-03-24T12:30" in ID3v2.3 frames: TYER only
+    /// holds the four-digit year, so the day and month of a full date go in
+    /// TDAT and the hour and minute in TIME
+    pub fn set_v23_date(&mut self, date: Option<&str>) {
+        self.remove("TYER");
+        self.remove("TDAT");
+        self.remove("TIME");
+        let Some(date) = date else { return };
+        self.frames.push(Frame::text("TYER", date.get(..4).unwrap_or(date), 3));
+        let digits = |range| date.get(range).filter(|text: &&str| text.bytes().all(|b| b.is_ascii_digit()));
+        if let (Some(month), Some(day)) = (digits(5..7), digits(8..10)) {
+            self.frames.push(Frame::text("TDAT", &format!("{}{}", day, month), 3));
+            if let (Some(hour), Some(minute)) = (digits(11..13), digits(14..16)) {
+                self.frames.push(Frame::text("TIME", &format!("{}{}", hour, minute), 3));
+            }
+        }
+    }
+
+    /// Remove every frame with the given ID
+    pub fn remove(&mut self, id: &str) {
+        self.frames.retain(|frame| frame.id != id);
+    }
+
+    /// Replace every frame with the same ID as `frame`
+    pub fn replace(&mut self, frame: Frame) {
+        let id = frame.id.clone();
+        // Keep the frame where the first existing one was, dropping any duplicates
+        let position = self.frames.iter().position(|f| f.id == id);
+        self.frames.retain(|f| f.id != id);
+        match position {
+            Some(pos) => self.frames.insert(pos, frame),
+            None => self.frames.push(frame),
+        }
+    }
+
+    /// Serialize the tag with `padding` zero bytes after the frames
+    pub fn to_bytes(&self, padding: usize) -> Result<Vec<u8>> {
+        let frames = self.frame_bytes()?;
+        let mut out = Vec::with_capacity(HEADER_LEN + frames.len() + padding);
+        out.extend_from_slice(&self.header(frames.len() + padding)?);
+        out.extend(frames);
+        out.resize(out.len() + padding, 0);
+        Ok(out)
+    }
+
+    /// Write the tag to the start of `path`, replacing any existing ID3v2 tag.
+    /// When the new tag fits in the old tag's space it is padded to the same
+    /// length, so the audio keeps its offset; otherwise it gets fresh padding.
+    /// Either way the whole file is copied and replaced atomically, so an
+    /// interrupted write leaves the original intact.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        self.write(path, false)
+    }
+
+    /// Like `write_to_path`, but also remove any ID3v1 trailer, and remove the
+    /// ID3v2 tag altogether if it has no frames
+    pub fn write_stripped(&self, path: &Path) -> Result<()> {
+        self.write(path, true)
+    }
+
+    fn write(&self, path: &Path, strip: bool) -> Result<()> {
+        let mut file = File::open(path).map_err(Error::io(path))?;
+        let existing_len = tag_len(&mut file).map_err(Error::io(path))?.unwrap_or(0);
+        let file_len = file.metadata().map_err(Error::io(path))?.len();
+        let trailer_len = if strip { v1_len(&mut file).map_err(Error::io(path))? } else { 0 };
+        drop(file);
+        let frames = self.frame_bytes()?;
+
+        let bytes = if strip && frames.is_empty() {
+            Vec::new()
+        } else if existing_len >= HEADER_LEN + frames.len() && existing_len > 0 {
+            let padding = existing_len - HEADER_LEN - frames.len();
+            let mut bytes = self.header(frames.len() + padding)?.to_vec();
+            bytes.extend(frames);
+            bytes.resize(existing_len, 0);
+            bytes
+        } else {
+            self.to_bytes(DEFAULT_PADDING)?
+        };
+        let audio_end = file_len.saturating_sub(trailer_len).max(existing_len as u64);
+        atomic::replace_range(path, &bytes, existing_len as u64..audio_end)
+    }
+
+    fn header(&self, body_len: usize) -> Result<[u8; HEADER_LEN]> {
+        if body_len >= 1 << 28 {
+            return Err(Error::InvalidInput(format!("ID3 tag is too large ({} bytes)", body_len)));
+        }
+        let mut header = [0; HEADER_LEN];
+        header[0..3].copy_from_slice(b"ID3");
+        header[3] = self.version;
+        header[6..10].copy_from_slice(&u32_to_syncsafe(body_len as u32));
+        Ok(header)
+    }
+
+    fn frame_bytes(&self) -> Result<Vec<u8>> {
+        if self.version != 3 && self.version != 4 {
+            return Err(Error::InvalidInput(format!("Can only write ID3v2.3 or ID3v2.4 tags, not 2.{}", self.version)));
+        }
+        let mut out = Vec::new();
+        for frame in &self.frames {
+            let Some(frame) = self.convert_frame(frame) else {
+                continue;
+            };
+            let flags = match frame.opaque {
+                Some((_, flags)) => flags,
+                None => 0,
+            };
+            if frame.id.len() != 4 {
+                return Err(Error::InvalidInput(format!("Invalid ID3 frame ID: {}", frame.id)));
+            }
+            let size = frame.data.len() as u32;
+            out.extend_from_slice(frame.id.as_bytes());
+            if self.version == 4 {
+                if size >= 1 << 28 {
+                    return Err(Error::InvalidInput(format!("ID3 frame {} is too large", frame.id)));
+                }
+                out.extend_from_slice(&u32_to_syncsafe(size));
+            } else {
+                out.extend_from_slice(&size.to_be_bytes());
+            }
+            out.extend_from_slice(&flags.to_be_bytes());
+            out.extend_from_slice(&frame.data);
+        }
+        Ok(out)
+    }
+
+    /// Adapt a frame to this tag's version, or drop it if it cannot be represented
+    fn convert_frame(&self, frame: &Frame) -> Option<Frame> {
+        if let Some((version, _)) = frame.opaque {
+            return (version == self.version).then(|| frame.clone());
+        }
+        let year = |frame: &Frame| frame.text_values().and_then(|v| v.into_iter().next());
+        match (self.version, frame.id.as_str()) {
+            (3, "TDRC") => year(frame).map(|date| {
+                let year: String = date.chars().take(4).collect();
+                Frame::text("TYER", &year, 3)
+            }),
+            // The v2.3 date frames become one TDRC frame, unless the tag already has one
+            (4, "TYER") if !self.frames.iter().any(|f| f.id == "TDRC") => self.v23_date().map(|date| Frame::text("TDRC", &date, 4)),
+            (4, "TYER" | "TDAT" | "TIME") => None,
+            // UTF-8 is not valid in v2.3, so re-encode text frames, and the
+            // strings of other frames with a known layout as UTF-16
+            (3, id) if frame.data.first() == Some(&TextEncoding::Utf8.byte()) => match frame.text_values() {
+                Some(values) => Some(Frame::text(id, &values.join(&self.v23_separator), 3)),
+                None => Some(utf8_to_utf16(frame).unwrap_or_else(|| frame.clone())),
+            },
+            _ => Some(frame.clone()),
+        }
+    }
+}
+
+/// Re-encode the UTF-8 strings of a TXXX, COMM, USLT, WXXX or APIC frame as
+/// UTF-16, or `None` for other frames
+fn utf8_to_utf16(frame: &Frame) -> Option<Frame> {
+    let rest = frame.data.get(1..)?;
+    // Bytes before the first encoded string, the number of encoded strings,
+    // and whether unencoded data (a URL or an image) follows them
+    let (prefix, strings, tail) = match frame.id.as_str() {
+        "TXXX" => (0, 2, false),
+        "COMM" | "USLT" => (3, 2, false),
+        "WXXX" => (0, 1, true),
+        // The Latin-1 MIME type and the picture type come before the description
+        "APIC" => (rest.iter().position(|&b| b == 0)? + 2, 1, true),
+        _ => return None,
+    };
+    let mut data = vec![TextEncoding::Utf16.byte()];
+    data.extend_from_slice(rest.get(..prefix)?);
+    let mut remaining = &rest[prefix..];
+    for index in 0..strings {
+        let (text, after) = split_terminated(remaining, TextEncoding::Utf8);
+        data.extend(TextEncoding::Utf16.encode(&TextEncoding::Utf8.decode(text)));
+        if index + 1 < strings || tail {
+            data.extend_from_slice(TextEncoding::Utf16.terminator());
+        }
+        remaining = after;
+    }
+    if tail {
+        data.extend_from_slice(remaining);
+    }
+    Some(Frame::new(&frame.id, data))
+}
+
+/// The genres of the ID3v1 genre list, which TCON frames may refer to by number
+const GENRES: [&str; 80] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz", "Metal",
+    "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno", "Industrial",
+    "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk",
+    "Fusion", "Trance", "Classical", "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+    "AlternRock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic",
+    "Darkwave", "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta",
+    "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave", "Psychadelic", "Rave", "Showtunes",
+    "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock",
+];
+
+/// Resolve a TCON value to genre names. ID3v2.3 refers to genres as "(17)"
+/// followed by an optional refinement, e.g. "(17)Indie Rock"; v2.4 uses bare
+/// numbers. "RX" and "CR" stand for remix and cover, and "((" escapes a "(".
+pub fn genre_names(value: &str) -> Vec<String> {
+    let name = |reference: &str| match reference {
+        "RX" => Some("Remix".to_string()),
+        "CR" => Some("Cover".to_string()),
+        number => number.parse::<usize>().ok().and_then(|n| GENRES.get(n)).map(|name| name.to_string()),
+    };
+    let mut names = Vec::new();
+    let mut rest = value;
+    while let Some(reference) = rest.strip_prefix('(').filter(|r| !r.starts_with('(')) {
+        let Some((reference, after)) = reference.split_once(')') else { break };
+        let Some(name) = name(reference) else { break };
+        names.push(name);
+        rest = after;
+    }
+    let refinement = rest.strip_prefix('(').filter(|r| r.starts_with('(')).unwrap_or(rest);
+    if !refinement.is_empty() {
+        // A refinement describes the referenced genres more precisely
+        return vec![name(refinement).unwrap_or_else(|| refinement.to_string())];
+    }
+    names
+}
+
+/// Read `path`'s ID3v2 tag (or start an empty one), apply `edit`, and write it back
+pub fn update(path: &Path, edit: impl FnOnce(&mut Id3Tag)) -> Result<()> {
+    let mut tag = Id3Tag::read_from_path(path)?.unwrap_or_default();
+    edit(&mut tag);
+    tag.write_to_path(path)
+}
+
+/// Like `update`, but write the tag with `Id3Tag::write_stripped`
+pub fn update_stripped(path: &Path, edit: impl FnOnce(&mut Id3Tag)) -> Result<()> {
+    let mut tag = Id3Tag::read_from_path(path)?.unwrap_or_default();
+    edit(&mut tag);
+    tag.write_stripped(path)
+}
+
+/// Size of the ID3v1 tag at the end of a file: 128 bytes, plus 227 for an
+/// enhanced "TAG+" block in front of it, or 0 if there is none
+fn v1_len(file: &mut File) -> std::io::Result<u64> {
+    let len = file.metadata()?.len();
+    let mut marker = [0; 4];
+    for (size, expected) in [(128 + 227, &b"TAG+"[..]), (128, &b"TAG"[..])] {
+        if len < size {
+            continue;
+        }
+        file.seek(SeekFrom::Start(len - size))?;
+        file.read_exact(&mut marker[..expected.len()])?;
+        if &marker[..expected.len()] == expected {
+            return Ok(size);
+        }
+    }
+    Ok(0)
+}
+
+/// A summary of the ID3v1 tag at the end of `path` ("Artist - Title"), if it has one
+pub fn read_v1_summary(path: &Path) -> Result<Option<String>> {
+    let mut file = File::open(path).map_err(Error::io(path))?;
+    if v1_len(&mut file).map_err(Error::io(path))? == 0 {
+        return Ok(None);
+    }
+    let mut tag = [0; 128];
+    file.seek(SeekFrom::End(-128))
+        .and_then(|_| file.read_exact(&mut tag))
+        .map_err(Error::io(path))?;
+    let text = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        TextEncoding::Latin1.decode(&bytes[..end]).trim().to_string()
+    };
+    Ok(Some(format!("{} - {}", text(&tag[33..63]), text(&tag[3..33]))))
+}
+
+/// Total on-disk length of the tag at the start of `file` (header, body and footer)
+pub(crate) fn tag_len(file: &mut File) -> std::io::Result<Option<usize>> {
+    let mut header = [0; HEADER_LEN];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if &header[0..3] != b"ID3" {
+        return Ok(None);
+    }
+    let size = syncsafe_to_u32(&header[6..10]) as usize;
+    let footer = if header[3] == 4 && header[5] & 0x10 != 0 { HEADER_LEN } else { 0 };
+    Ok(Some(HEADER_LEN + size + footer))
+}
+
+fn parse_frames(body: &[u8], version: u8, tag_unsync: bool) -> ParseResult<Vec<Frame>> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos + HEADER_LEN <= body.len() {
+        let id = &body[pos..pos + 4];
+        if !id.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            // Padding (or garbage) - no more frames
+            break;
+        }
+        let size = if version == 4 {
+            syncsafe_to_u32(&body[pos + 4..pos + 8])
+        } else {
+            read_u32_be(body, pos + 4)?
+        } as usize;
+        let flags = u16::from_be_bytes([body[pos + 8], body[pos + 9]]);
+        pos += HEADER_LEN;
+        if pos + size > body.len() {
+            return Err(ParseError(format!("ID3 frame {} overruns the tag", String::from_utf8_lossy(id))));
+        }
+        let id = String::from_utf8_lossy(id).into_owned();
+        let data = &body[pos..pos + size];
+        pos += size;
+
+        frames.push(if version == 4 {
+            normalise_v24_frame(id, data, flags, tag_unsync)?
+        } else {
+            normalise_v23_frame(id, data, flags)?
+        });
+    }
+    Ok(frames)
+}
+
+fn normalise_v23_frame(id: String, data: &[u8], flags: u16) -> ParseResult<Frame> {
+    const COMPRESSION: u16 = 0x0080;
+    const ENCRYPTION: u16 = 0x0040;
+    const GROUPING: u16 = 0x0020;
+
+    if flags & (COMPRESSION | ENCRYPTION) != 0 {
+        return Ok(Frame { id, data: data.to_vec(), opaque: Some((3, flags)) });
+    }
+    let skip = if flags & GROUPING != 0 { 1 } else { 0 };
+    if data.len() < skip {
+        return Err(ParseError(format!("ID3 frame {} is truncated", id)));
+    }
+    Ok(Frame::new(&id, data[skip..].to_vec()))
+}
+
+fn normalise_v24_frame(id: String, data: &[u8], flags: u16, tag_unsync: bool) -> ParseResult<Frame> {
+    const GROUPING: u16 = 0x0040;
+    const COMPRESSION: u16 = 0x0008;
+    const ENCRYPTION: u16 = 0x0004;
+    const UNSYNC: u16 = 0x0002;
+    const DATA_LENGTH: u16 = 0x0001;
+
+    if flags & (COMPRESSION | ENCRYPTION) != 0 {
+        return Ok(Frame { id, data: data.to_vec(), opaque: Some((4, flags)) });
+    }
+    let mut skip = 0;
+    if flags & GROUPING != 0 {
+        skip += 1;
+    }
+    if flags & DATA_LENGTH != 0 {
+        skip += 4;
+    }
+    if data.len() < skip {
+        return Err(ParseError(format!("ID3 frame {} is truncated", id)));
+    }
+    let data = if flags & UNSYNC != 0 || tag_unsync {
+        remove_unsync(&data[skip..])
+    } else {
+        data[skip..].to_vec()
+    };
+    Ok(Frame::new(&id, data))
+}
+
+fn parse_v22_frames(body: &[u8]) -> ParseResult<Vec<Frame>> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos + 6 <= body.len() {
+        let id = &body[pos..pos + 3];
+        if !id.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            break;
+        }
+        let size = u32::from_be_bytes([0, body[pos + 3], body[pos + 4], body[pos + 5]]) as usize;
+        pos += 6;
+        if pos + size > body.len() {
+            return Err(ParseError(format!("ID3 frame {} overruns the tag", String::from_utf8_lossy(id))));
+        }
+        let data = &body[pos..pos + size];
+        pos += size;
+
+        let id = String::from_utf8_lossy(id);
+        if id == "PIC" {
+            if let Some(frame) = convert_v22_picture(data) {
+                frames.push(frame);
+            }
+        } else if let Some(new_id) = v22_frame_id(&id) {
+            frames.push(Frame::new(new_id, data.to_vec()));
+        }
+    }
+    Ok(frames)
+}
+
+/// ID3v2.2 frame IDs and their v2.3 equivalents. Frames without an
+/// equivalent are dropped when upgrading.
+fn v22_frame_id(id: &str) -> Option<&'static str> {
+    Some(match id {
+        "TT1" => "TIT1",
+        "TT2" => "TIT2",
+        "TT3" => "TIT3",
+        "TP1" => "TPE1",
+        "TP2" => "TPE2",
+        "TP3" => "TPE3",
+        "TP4" => "TPE4",
+        "TAL" => "TALB",
+        "TRK" => "TRCK",
+        "TPA" => "TPOS",
+        "TYE" => "TYER",
+        "TDA" => "TDAT",
+        "TIM" => "TIME",
+        "TCO" => "TCON",
+        "TCM" => "TCOM",
+        "TCR" => "TCOP",
+        "TBP" => "TBPM",
+        "TRC" => "TSRC",
+        "TEN" => "TENC",
+        "TSS" => "TSSE",
+        "TLE" => "TLEN",
+        "TPB" => "TPUB",
+        "TXT" => "TEXT",
+        "TXX" => "TXXX",
+        "COM" => "COMM",
+        "ULT" => "USLT",
+        "WXX" => "WXXX",
+        "UFI" => "UFID",
+        _ => return None,
+    })
+}
+
+/// Convert a v2.2 PIC frame (three-letter image format) into a v2.3 APIC frame (MIME type)
+fn convert_v22_picture(data: &[u8]) -> Option<Frame> {
+    if data.len() < 5 {
+        return None;
+    }
+    let format = String::from_utf8_lossy(&data[1..4]).to_ascii_lowercase();
+    let mime = match format.as_str() {
+        "jpg" => "image/jpeg".to_string(),
+        "-->" => "-->".to_string(),
+        other => format!("image/{}", other),
+    };
+    let mut out = vec![data[0]];
+    out.extend_from_slice(mime.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&data[4..]);
+    Some(Frame::new("APIC", out))
+}
+
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &byte in data {
+        if !(previous == 0xFF && byte == 0x00) {
+            out.push(byte);
+        }
+        previous = byte;
+    }
+    out
+}
+
+fn read_u32_be(bytes: &[u8], pos: usize) -> ParseResult<u32> {
+    bytes
+        .get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| ParseError("ID3 tag is truncated".to_string()))
+}
+
+fn syncsafe_to_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7F) as u32)
+}
+
+fn u32_to_syncsafe(value: u32) -> [u8; 4] {
+    [
+        ((value >> 21) & 0x7F) as u8,
+        ((value >> 14) & 0x7F) as u8,
+        ((value >> 7) & 0x7F) as u8,
+        (value & 0x7F) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_round_trip_text_frames() {
+        for version in [3, 4] {
+            let mut tag = Id3Tag::new(version);
+            tag.replace(Frame::text("TPE1", "Sigur Rós", tag.version));
+            tag.replace(Frame::text("TIT2", "日本語", tag.version));
+            tag.replace(Frame::text("TRCK", "7", tag.version));
+            tag.replace(Frame::text("TPE1", "Björk", tag.version));
+
+            let parsed = Id3Tag::parse(&tag.to_bytes(32).unwrap()).unwrap();
+            assert_eq!(parsed.version, version);
+            assert_eq!(parsed.frames.len(), 3);
+            assert_eq!(parsed.text_values("TPE1"), vec!["Björk"]);
+            assert_eq!(parsed.text_values("TIT2"), vec!["日本語"]);
+            assert_eq!(parsed.text_values("TRCK"), vec!["7"]);
+
+            // Only v2.4 can keep several values apart
+            let artists = ["A".to_string(), "B".to_string()];
+            tag.replace(Frame::text_list("TPE1", &artists, version, "; "));
+            let parsed = Id3Tag::parse(&tag.to_bytes(0).unwrap()).unwrap();
+            let expected: &[&str] = if version == 4 { &["A", "B"] } else { &["A; B"] };
+            assert_eq!(parsed.text_values("TPE1"), expected);
+        }
+    }
+
+    #[test]
+    fn test_v23_reencodes_utf8_frames() {
+        // Frames built for v2.4 are UTF-8, which v2.3 does not allow
+        let picture = Picture {
+            picture_type: Picture::FRONT_COVER,
+            mime_type: "image/png".to_string(),
+            description: "Façade".to_string(),
+            width: 0,
+            height: 0,
+            color_depth: 0,
+            colors: 0,
+            data: vec![0, 1, 2],
+        };
+        let mut tag = Id3Tag::new(3);
+        tag.v23_separator = "; ".to_string();
+        tag.frames.push(Frame::text_list("TPE1", &["Björk".to_string(), "Sigur Rós".to_string()], 4, "/"));
+        tag.frames.push(Frame::user_text("Étiquette", "Ünïcode", 4));
+        tag.frames.push(Frame::described_text("COMM", "", "Très bien", 4));
+        tag.frames.push(Frame::described_text("USLT", "", "Zeile\nZwei", 4));
+        tag.frames.push(Frame::from_picture(&picture, 4));
+
+        let parsed = Id3Tag::parse(&tag.to_bytes(0).unwrap()).unwrap();
+        assert!(parsed.frames.iter().all(|frame| frame.data[0] != TextEncoding::Utf8.byte()));
+        assert_eq!(parsed.text_values("TPE1"), vec!["Björk; Sigur Rós"]);
+        assert_eq!(parsed.user_text_values("Étiquette"), vec!["Ünïcode"]);
+        assert_eq!(parsed.described_values("COMM", ""), vec!["Très bien"]);
+        assert_eq!(parsed.described_values("USLT", ""), vec!["Zeile\nZwei"]);
+        assert_eq!(parsed.frames[4].picture().unwrap(), picture);
+    }
+
+    #[test]
+    fn test_v23_date_upgrades_to_tdrc() {
+        let mut tag = Id3Tag::new(3);
+        tag.set_v23_date(Some("1999-03-24T12:30"));
+        assert_eq!(tag.text_values("TIME"), vec!["1230"]);
+
+        // Upgrading to v2.4 merges the v2.3 date frames into TDRC
+        let mut upgraded = Id3Tag::parse(&tag.to_bytes(0).unwrap()).unwrap();
+        upgraded.version = 4;
+        let parsed = Id3Tag::parse(&upgraded.to_bytes(0).unwrap()).unwrap();
+        assert_eq!(parsed.frames.len(), 1);
+        assert_eq!(parsed.text_values("TDRC"), vec!["1999-03-24T12:30"]);
+
+        // An existing TDRC frame wins over the v2.3 frames
+        upgraded.frames.push(Frame::text("TDRC", "2001", 4));
+        let parsed = Id3Tag::parse(&upgraded.to_bytes(0).unwrap()).unwrap();
+        assert_eq!(parsed.frames.len(), 1);
+        assert_eq!(parsed.text_values("TDRC"), vec!["2001"]);
+    }
+
+    #[test]
+    fn test_comments_and_genres() {
+        for version in [3, 4] {
+            let mut tag = Id3Tag::new(version);
+            tag.frames.push(Frame::described_text("COMM", "", "Great", version));
+            tag.frames.push(Frame::described_text("COMM", "iTunNORM", "0000", version));
+            tag.frames.push(Frame::described_text("USLT", "", "Ünïcode\nlyrics", version));
+
+            let parsed = Id3Tag::parse(&tag.to_bytes(0).unwrap()).unwrap();
+            assert_eq!(parsed.described_values("COMM", ""), vec!["Great"]);
+            assert_eq!(parsed.described_values("COMM", "iTunNORM"), vec!["0000"]);
+            assert_eq!(parsed.described_values("USLT", ""), vec!["Ünïcode\nlyrics"]);
+        }
+
+        assert_eq!(genre_names("(17)"), vec!["Rock"]);
+        assert_eq!(genre_names("(17)(20)"), vec!["Rock", "Alternative"]);
+        assert_eq!(genre_names("(17)Indie Rock"), vec!["Indie Rock"]);
+        assert_eq!(genre_names("52"), vec!["Electronic"]);
+        assert_eq!(genre_names("((Not a reference)"), vec!["(Not a reference)"]);
+        assert_eq!(genre_names("Shoegaze"), vec!["Shoegaze"]);
+    }
+
+    #[test]
+    fn test_parse_v22_upgrades_frames() {
+        let mut body = Vec::new();
+        for (id, text) in [("TT2", "Title"), ("TP1", "Artist"), ("ZZZ", "dropped")] {
+            body.extend_from_slice(id.as_bytes());
+            body.extend_from_slice(&((text.len() + 1) as u32).to_be_bytes()[1..]);
+            body.push(0);
+            body.extend_from_slice(text.as_bytes());
+        }
+        let mut bytes = b"ID3\x02\x00\x00".to_vec();
+        bytes.extend_from_slice(&u32_to_syncsafe(body.len() as u32));
+        bytes.extend(body);
+
+        let tag = Id3Tag::parse(&bytes).unwrap();
+        assert_eq!(tag.version, 3);
+        assert_eq!(tag.frames.len(), 2);
+        assert_eq!(tag.text_values("TIT2"), vec!["Title"]);
+        assert_eq!(tag.text_values("TPE1"), vec!["Artist"]);
+    }
+
+    #[test]
+    fn test_write_to_path_reuses_padding() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-id3-{}.mp3", std::process::id()));
+        fs::write(&path, b"\xFF\xFBaudio").unwrap();
+
+        update(&path, |tag| tag.replace(Frame::text("TIT2", "First", tag.version))).unwrap();
+        let first_len = fs::metadata(&path).unwrap().len();
+        update(&path, |tag| tag.replace(Frame::text("TALB", "Album", tag.version))).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len() as u64, first_len);
+        assert!(bytes.ends_with(b"\xFF\xFBaudio"));
+        let tag = Id3Tag::parse(&bytes).unwrap();
+        assert_eq!(tag.text_values("TIT2"), vec!["First"]);
+        assert_eq!(tag.text_values("TALB"), vec!["Album"]);
+    }
+
+    #[test]
+    fn test_write_stripped_removes_v1_trailer() {
+        let path = std::env::temp_dir().join(format!("audio-metadata-id3-strip-{}.mp3", std::process::id()));
+        let mut bytes = b"\xFF\xFBaudio".to_vec();
+        bytes.extend_from_slice(b"TAGTitle");
+        bytes.resize(bytes.len() + 120, 0);
+        fs::write(&path, &bytes).unwrap();
+        assert!(read_v1_summary(&path).unwrap().is_some());
+
+        update_stripped(&path, |tag| tag.replace(Frame::text("TIT2", "Kept", tag.version))).unwrap();
+        let tagged = fs::read(&path).unwrap();
+        update_stripped(&path, |tag| tag.frames.clear()).unwrap();
+        let stripped = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(tagged.ends_with(b"\xFF\xFBaudio"));
+        assert_eq!(Id3Tag::parse(&tagged).unwrap().text_values("TIT2"), vec!["Kept"]);
+        assert_eq!(stripped, b"\xFF\xFBaudio");
+    }
+}
@@ -0,0 +1,60 @@
+//! Read and write audio file metadata and convert lossless audio files to
+//! MP3, Opus, Ogg Vorbis, AAC or ALAC.
+//!
+//! Tags are handled through the format-agnostic [`Tag`] model: [`read_tags`]
+//! and [`update_tag`] work on any supported file, while [`format_for_path`]
+//! exposes the underlying [`AudioFormat`] implementation for lower-level use.
+//! The `set_*` helpers change a single field and are convenient for one-off edits;
+//! build a [`TagUpdate`] to change several fields with one write.
+//!
+//! ```no_run
+//! use audio_metadata::{Field, TagUpdate, update_tag};
+//! use std::path::Path;
+//!
+//! let mut update = TagUpdate::default();
+//! update.set(Field::Artist, "Artist Name");
+//! update.set(Field::Album, "Album Title");
+//! update_tag(Path::new("song.mp3"), &update)?;
+//! # Ok::<(), audio_metadata::Error>(())
+//! ```
+
+mod atomic;
+pub mod backup;
+pub mod convert;
+pub mod cover;
+pub mod error;
+pub mod file_ops;
+mod flac;
+pub mod format;
+mod id3;
+pub mod metadata;
+mod ogg;
+pub mod pattern;
+pub mod picture;
+pub mod pool;
+pub mod progress;
+pub mod rename;
+pub mod tag;
+mod tool;
+pub mod walk;
+
+pub use backup::{BackupPolicy, BackupRun, ManifestEntry, Restored, RunInfo, list_runs, restore_entry};
+pub use convert::{ConvertOptions, Mp3Preset, Target, convert_file, convert_single_file};
+pub use cover::{CoverAction, CoverOptions, process_covers};
+pub use error::{Error, Result};
+pub use file_ops::{
+    CoverSource, SetOptions, collect_audio_files, process_directory, process_directory_conversion, process_single_file,
+};
+pub use format::{AudioFormat, WriteOptions, format_for_path};
+pub use metadata::{
+    FileTags, convert_flac_to_mp3, convert_to_mp3, infer_fields_from_filename, infer_track_name_from_filename, load_cover_art,
+    load_picture, read_tags, set_album_title, set_artist, set_artists, set_cover_art, set_title, set_track_number, update_tag,
+    update_tag_with,
+};
+pub use pattern::FilenamePattern;
+pub use picture::Picture;
+pub use pool::default_jobs;
+pub use progress::Progress;
+pub use rename::{NameTemplate, RenameOptions, rename_files};
+pub use tag::{Field, Id3Frame, Tag, TagItem, TagUpdate};
+pub use walk::{SymlinkPolicy, WalkOptions, collect_file_groups};
@@ -1,131 +1,323 @@
+mod backups;
 mod cli;
-mod file_ops;
-mod metadata;
+mod config;
+mod restore;
+mod show;
 
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::num::NonZeroUsize;
+use std::process::ExitCode;
 
-use crate::cli::{Cli, Commands};
-use crate::file_ops::{process_directory, process_directory_conversion};
-use crate::metadata::{convert_flac_to_mp3};
+use audio_metadata::{
+    ConvertOptions, CoverAction, CoverOptions, CoverSource, Error, Mp3Preset, Progress, SetOptions, Target, WriteOptions,
+    convert_single_file, default_jobs, load_picture, RenameOptions, process_covers, process_directory,
+    process_directory_conversion, process_single_file, rename_files,
+};
 
-fn main() -> Result<()> {
+use crate::cli::{BackupsCommand, Cli, Commands, CoverCommand};
+use crate::config::Values;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        // Library errors carry their own message and exit code; anything else is a generic failure
+        Err(e) => match e.downcast_ref::<Error>() {
+            Some(error) => {
+                eprintln!("Error: {}", error);
+                ExitCode::from(error.exit_code())
+            }
+            None => {
+                eprintln!("Error: {:#}", e);
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+fn run() -> Result<()> {
     let cli = Cli::parse();
 
+    // Config files give the defaults; options on the command line override them
+    let target = match &cli.command {
+        Commands::Set { file, .. } | Commands::Convert { file, .. } | Commands::Rename { file, .. } | Commands::Show { file, .. } => {
+            Path::new(file)
+        }
+        Commands::Cover { command } => Path::new(command.file()),
+        _ => Path::new("."),
+    };
+    let config_files: Vec<PathBuf> = match cli.config {
+        Some(path) => vec![path],
+        None => config::user_config_path()
+            .filter(|path| path.is_file())
+            .into_iter()
+            .chain(config::find_project_config(target))
+            .collect(),
+    };
+    let settings = config::load(&config_files, cli.profile.as_deref())?;
+
+    let mut policy = settings.backup_policy()?;
+    if let Some(dir) = cli.backup_dir {
+        policy.root = dir;
+    }
+    policy.max_age = cli.backup_max_age.or(policy.max_age);
+    policy.max_size = cli.backup_max_size.or(policy.max_size);
+
     match cli.command {
-        Commands::Set { file, cover, album, artist, title, track, infer_track, infer_order } => {
+        Commands::Set {
+            file, cover, album, artist, title, fields, track, track_total, disc, disc_total, id3v23_separator, infer_track,
+            from_filename, infer_order, dry_run, no_backup, walk, jobs,
+        } => {
             let path = PathBuf::from(file);
-            
-            if path.is_dir() {
-                // Create a single temp directory for all files
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                let temp_dir = PathBuf::from(format!("/tmp/audio-metadata-{}", timestamp));
-                fs::create_dir(&temp_dir)
-                    .with_context(|| format!("Failed to create temp directory: {}", temp_dir.display()))?;
-                
-                process_directory(
-                    &path,
-                    cover.as_ref().map(PathBuf::from),
-                    album.as_deref(),
-                    artist.as_deref(),
-                    title.as_deref(),
-                    track,
-                    infer_track,
-                    infer_order,
-                    &temp_dir
-                )?;
-                
-                println!("\nAll files have been processed.");
-                println!("Original files are backed up in: {}", temp_dir.display());
-                println!("You can safely delete the backup directory when you're satisfied with the changes.");
-            } else {
-                // Create a temp directory for the single file
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                let temp_dir = PathBuf::from(format!("/tmp/audio-metadata-{}", timestamp));
-                fs::create_dir(&temp_dir)
-                    .with_context(|| format!("Failed to create temp directory: {}", temp_dir.display()))?;
-
-                // Process each metadata operation
-                if let Some(cover_path) = cover {
-                    metadata::set_cover_art_with_temp(&path, &PathBuf::from(cover_path), &temp_dir)?;
-                }
-                if let Some(album_title) = album {
-                    metadata::set_album_title(&path, &album_title)?;
+            let defaults = settings.set;
+            let lyrics = match fields.lyrics_file {
+                Some(lyrics_path) => {
+                    let text = fs::read_to_string(&lyrics_path).map_err(|source| Error::Io { path: lyrics_path, source })?;
+                    Some(text.trim_end().to_string())
                 }
-                if let Some(artist_name) = artist {
-                    metadata::set_artist(&path, &artist_name)?;
-                }
-                if let Some(song_title) = title {
-                    metadata::set_title_with_temp(&path, &song_title, &temp_dir)?;
-                }
-                if let Some(track_number) = track {
-                    metadata::set_track_number(&path, track_number)?;
-                    println!("Set track number {} for {}", track_number, path.display());
-                }
-                if infer_track {
-                    let inferred_title = metadata::infer_track_name_from_filename(&path)?;
-                    metadata::set_title_with_temp(&path, &inferred_title, &temp_dir)?;
+                None => fields.lyrics,
+            };
+            let options = SetOptions {
+                cover: cover.map(PathBuf::from).or(defaults.cover).map(CoverSource::from),
+                album: album.or(defaults.album),
+                artist: values_or(artist, defaults.artist),
+                title,
+                album_artist: values_or(fields.album_artist, defaults.album_artist),
+                date: fields.date,
+                genre: values_or(fields.genre, defaults.genre),
+                composer: values_or(fields.composer, defaults.composer),
+                performer: fields.performer,
+                comment: fields.comment,
+                bpm: fields.bpm,
+                isrc: fields.isrc,
+                copyright: fields.copyright.or(defaults.copyright),
+                lyrics,
+                tags: fields.tag,
+                remove_tags: fields.remove_tag,
+                clear: fields.clear,
+                keep: if fields.strip_all { Some(Vec::new()) } else { fields.keep },
+                track,
+                track_total,
+                disc,
+                disc_total,
+                infer_track: infer_track || defaults.infer_track.unwrap_or(false),
+                from_filename: from_filename.or(defaults.from_filename),
+                infer_order: infer_order || defaults.infer_order.unwrap_or(false),
+                dry_run,
+                write: match id3v23_separator.or(defaults.id3v23_separator) {
+                    Some(separator) => WriteOptions { id3v23_separator: separator, ..WriteOptions::default() },
+                    None => WriteOptions::default(),
+                },
+            };
+
+            // A single backup run for all files, created when the first file is backed up
+            policy.enabled &= !no_backup;
+            let backup = policy.start(&path, "set");
+
+            let mut result = Ok(());
+            if path.is_dir() {
+                let jobs = jobs.or(settings.jobs).map_or_else(default_jobs, NonZeroUsize::get);
+                let walk = walk.with_defaults(settings.walk);
+                let run = process_directory(&path, &options, &walk, jobs, &backup, &mut |p| print_progress("processing", p));
+                result = defer_partial_failure(run)?;
+
+                if !dry_run {
+                    println!();
+                    if result.is_ok() {
+                        println!("All files have been processed.");
+                    }
+                    if let Some(dir) = backup.dir() {
+                        println!("Original files are backed up in: {}", dir.display());
+                        println!("You can safely delete the backup directory when you're satisfied with the changes,");
+                        println!("or undo them with: audio-metadata restore {}", backup_name(&dir));
+                    }
                 }
+            } else {
+                process_single_file(&path, &options, &backup, &mut |p| print_progress("processing", p))?;
 
-                println!("\nFile has been processed.");
-                println!("Original file is backed up in: {}", temp_dir.display());
-                println!("You can safely delete the backup directory when you're satisfied with the changes.");
+                if !dry_run {
+                    println!("\nFile has been processed.");
+                    if let Some(dir) = backup.dir() {
+                        println!("Original file is backed up in: {}", dir.display());
+                        println!("You can safely delete the backup directory when you're satisfied with the changes,");
+                        println!("or undo them with: audio-metadata restore {}", backup_name(&dir));
+                    }
+                }
             }
+            if dry_run {
+                println!("\nDry run: no files were changed.");
+            } else {
+                backups::apply_retention(&policy)?;
+            }
+            result?;
         }
-        Commands::Convert { file, output, bitrate } => {
+        Commands::Convert { file, output, to, bitrate, quality, abr, preset, dry_run, no_backup, walk, jobs } => {
             let input_path = PathBuf::from(file);
-            let output_dir = output.map(PathBuf::from);
+            let defaults = settings.convert;
+            let output_dir = output.map(PathBuf::from).or(defaults.output);
+            // Encoder settings from the config only apply if none are given on the command line
+            let (bitrate, quality, abr, preset) = if bitrate.is_none() && quality.is_none() && !abr && preset.is_none() {
+                (defaults.bitrate, defaults.quality, defaults.abr.unwrap_or(false), defaults.preset)
+            } else {
+                (bitrate, quality, abr, preset)
+            };
+            let options = ConvertOptions {
+                target: to.or(defaults.to).map_or_else(Target::default, Target::from),
+                bitrate,
+                quality,
+                abr,
+                preset: preset.map(Mp3Preset::from),
+                dry_run,
+            };
 
-            // Create a temp directory for backups
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            let temp_dir = PathBuf::from(format!("/tmp/audio-metadata-{}", timestamp));
-            fs::create_dir(&temp_dir)
-                .with_context(|| format!("Failed to create temp directory: {}", temp_dir.display()))?;
+            // Back up the inputs, and create the output directory if specified
+            policy.enabled &= !no_backup;
+            let backup = policy.start(&input_path, "convert");
+            if !dry_run && let Some(ref dir) = output_dir {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+            }
 
+            let mut result = Ok(());
             if input_path.is_dir() {
-                // Create output directory if specified
-                if let Some(ref dir) = output_dir {
-                    fs::create_dir_all(dir)
-                        .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
-                }
+                let jobs = jobs.or(settings.jobs).map_or_else(default_jobs, NonZeroUsize::get);
+                let walk = walk.with_defaults(settings.walk);
+                result = defer_partial_failure(process_directory_conversion(
+                    &input_path, output_dir.as_ref(), &options, &walk, jobs, &backup,
+                    &mut |p| print_progress("converting", p),
+                ))?;
 
-                process_directory_conversion(&input_path, output_dir.as_ref(), bitrate, &temp_dir)?;
-                
-                println!("\nAll files have been processed.");
-                println!("Original files are backed up in: {}", temp_dir.display());
-                println!("You can safely delete the backup directory when you're satisfied with the conversions.");
-            } else {
-                // Verify it's a FLAC file
-                if let Some(ext) = input_path.extension() {
-                    if ext.to_str().unwrap().to_lowercase() != "flac" {
-                        return Err(anyhow::anyhow!("Input file must be a FLAC file"));
+                if !dry_run {
+                    println!();
+                    if result.is_ok() {
+                        println!("All files have been processed.");
+                    }
+                    if let Some(dir) = backup.dir() {
+                        println!("Original files are backed up in: {}", dir.display());
+                        println!("You can safely delete the backup directory when you're satisfied with the conversions.");
                     }
-                } else {
-                    return Err(anyhow::anyhow!("Input file must have an extension"));
                 }
+            } else {
+                convert_single_file(&input_path, output_dir.as_deref(), &options, &backup, &mut |p| print_progress("converting", p))?;
 
-                // Create output directory if specified
-                if let Some(ref dir) = output_dir {
-                    fs::create_dir_all(dir)
-                        .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+                if let Some(dir) = backup.dir() {
+                    println!("Original file is backed up in: {}", dir.display());
+                    println!("You can safely delete the backup when you're satisfied with the conversion.");
                 }
+            }
+            if dry_run {
+                println!("\nDry run: no files were changed.");
+            } else {
+                backups::apply_retention(&policy)?;
+            }
+            result?;
+        }
+        Commands::Rename { file, template, output, dry_run, no_backup, walk } => {
+            let path = PathBuf::from(file);
+            let defaults = settings.rename;
+            let Some(template) = template.or(defaults.template) else {
+                bail!("No name template given: use --template or set one in the config file");
+            };
+            let options = RenameOptions {
+                template,
+                output_dir: output.map(PathBuf::from).or(defaults.output),
+                dry_run,
+            };
+
+            policy.enabled &= !no_backup;
+            let backup = policy.start(&path, "rename");
+            let walk = walk.with_defaults(settings.walk);
+            let result = defer_partial_failure(rename_files(&path, &options, &walk, &backup, &mut |p| print_progress("renaming", p)))?;
+
+            if dry_run {
+                println!("\nDry run: no files were renamed.");
+            } else {
+                if let Some(dir) = backup.dir() {
+                    println!("\nUndo the renames with: audio-metadata restore {}", backup_name(&dir));
+                }
+                backups::apply_retention(&policy)?;
+            }
+            result?;
+        }
+        Commands::Cover { command } => {
+            let walk_defaults = settings.walk;
+            let (file, options, walk, no_backup) = match command {
+                CoverCommand::Extract { file, output, picture_type, dry_run, walk } => {
+                    let action = CoverAction::Extract { output_dir: output.map(PathBuf::from) };
+                    (file, CoverOptions { action, types: picture_type, dry_run }, walk, true)
+                }
+                CoverCommand::Set { file, image, picture_type, description, mime, dry_run, no_backup, walk } => {
+                    let picture = load_picture(Path::new(&image), picture_type, &description, mime.as_deref())?;
+                    (file, CoverOptions { action: CoverAction::Set(picture), types: Vec::new(), dry_run }, walk, no_backup)
+                }
+                CoverCommand::Remove { file, picture_type, dry_run, no_backup, walk } => {
+                    (file, CoverOptions { action: CoverAction::Remove, types: picture_type, dry_run }, walk, no_backup)
+                }
+            };
+
+            // Extracting only reads the audio files, so nothing is backed up
+            let path = PathBuf::from(file);
+            policy.enabled &= !no_backup;
+            let backup = policy.start(&path, "cover");
+            let walk = walk.with_defaults(walk_defaults);
+            let result = defer_partial_failure(process_covers(&path, &options, &walk, &backup, &mut |p| print_progress("processing", p)))?;
 
-                convert_flac_to_mp3(&input_path, output_dir.as_ref(), bitrate)?;
+            if options.dry_run {
+                println!("\nDry run: no files were changed.");
+            } else {
+                if let Some(dir) = backup.dir() {
+                    println!("\nOriginal files are backed up in: {}", dir.display());
+                    println!("Undo the changes with: audio-metadata restore {}", backup_name(&dir));
+                }
+                backups::apply_retention(&policy)?;
             }
+            result?;
+        }
+        Commands::Show { file, json } => {
+            show::show(&PathBuf::from(file), json)?;
+        }
+        Commands::Restore { run, file } => {
+            restore::restore(&policy.root, run.as_deref(), &file)?;
+        }
+        Commands::Backups { command: BackupsCommand::Prune { dry_run } } => {
+            backups::prune(&policy, dry_run)?;
         }
     }
 
     Ok(())
 }
+
+/// Keep a partial failure to return once the run's summary has been printed:
+/// the files that failed were reported as they happened, and only decide the
+/// exit code. Any other error stops the command straight away.
+fn defer_partial_failure(result: audio_metadata::Result<()>) -> Result<audio_metadata::Result<()>> {
+    match result {
+        Err(e @ Error::PartialFailure { .. }) => Ok(Err(e)),
+        result => Ok(Ok(result?)),
+    }
+}
+
+/// Print the progress the library reports; `action` is what a failed file was
+/// being done, e.g. "processing" or "converting"
+fn print_progress(action: &str, progress: Progress) {
+    match progress {
+        Progress::File { result: Ok(message), .. } | Progress::Warning(message) => println!("{}", message),
+        Progress::File { path, result: Err(e) } => eprintln!("Error {} {}: {}", action, path.display(), e),
+    }
+}
+
+/// Values given on the command line, or else those from the config file
+fn values_or(values: Vec<String>, default: Option<Values>) -> Vec<String> {
+    if values.is_empty() {
+        default.map(Vec::from).unwrap_or_default()
+    } else {
+        values
+    }
+}
+
+/// Name of a backup run as accepted by `restore`
+fn backup_name(dir: &Path) -> String {
+    dir.file_name().unwrap().to_string_lossy().into_owned()
+}
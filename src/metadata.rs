@@ -1,341 +1,200 @@
-use anyhow::{Result, Context};
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use crate::backup::BackupRun;
+use crate::convert::{self, ConvertOptions, Target};
+use crate::error::{Error, ParseError, Result};
+use crate::format::{WriteOptions, format_for_path};
+use crate::pattern::FilenamePattern;
+use crate::picture::{Picture, image_info};
+use crate::tag::{Field, TagUpdate};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
-use regex;
-
-/// Set the artist metadata for an audio file
-pub fn set_artist(file_path: &PathBuf, artist: &str) -> Result<()> {
-    let extension = file_path.extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or_else(|| anyhow::anyhow!("File has no extension"))?;
-
-    match extension.to_lowercase().as_str() {
-        "flac" => set_flac_artist(file_path, artist),
-        "mp3" => set_mp3_artist(file_path, artist),
-        _ => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
-    }
-}
-
-fn set_flac_artist(file_path: &PathBuf, artist: &str) -> Result<()> {
-    // First remove any existing ARTIST tag
-    let status = Command::new("metaflac")
-        .args(["--remove-tag", "ARTIST", file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to remove existing tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while removing existing tag"));
-    }
 
-    // Then set the new ARTIST tag
-    let status = Command::new("metaflac")
-        .args(["--set-tag", &format!("ARTIST={}", artist), file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to set new tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while setting new tag"));
-    }
-
-    Ok(())
+/// Apply a set of tag changes to an audio file with a single read and write
+pub fn update_tag(file_path: &Path, update: &TagUpdate) -> Result<()> {
+    update_tag_with(file_path, update, &WriteOptions::default())
 }
 
-fn set_mp3_artist(file_path: &PathBuf, artist: &str) -> Result<()> {
-    let status = Command::new("id3v2")
-        .args(["--artist", artist, file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute id3v2 command")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("id3v2 command failed"));
-    }
-
-    Ok(())
+/// Like `update_tag`, with control over how values are written
+pub fn update_tag_with(file_path: &Path, update: &TagUpdate, options: &WriteOptions) -> Result<()> {
+    let format = format_for_path(file_path)?;
+    let mut tag = format.read_tag_with(file_path, options)?;
+    update.apply(&mut tag);
+    format.write_tag(file_path, &tag, options)
 }
 
-/// Set the album title metadata for an audio file
-pub fn set_album_title(file_path: &PathBuf, album_title: &str) -> Result<()> {
-    let extension = file_path.extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or_else(|| anyhow::anyhow!("File has no extension"))?;
-
-    match extension.to_lowercase().as_str() {
-        "flac" => set_flac_album_title(file_path, album_title),
-        "mp3" => set_mp3_album_title(file_path, album_title),
-        _ => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
-    }
+/// Load an image file as front cover art
+pub fn load_cover_art(cover_path: &Path) -> Result<Picture> {
+    load_picture(cover_path, Picture::FRONT_COVER, "", None)
 }
 
-fn set_flac_album_title(file_path: &PathBuf, album_title: &str) -> Result<()> {
-    // First remove any existing ALBUM tag
-    let status = Command::new("metaflac")
-        .args(["--remove-tag", "ALBUM", file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to remove existing tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while removing existing tag"));
-    }
-
-    // Then set the new ALBUM tag
-    let status = Command::new("metaflac")
-        .args(["--set-tag", &format!("ALBUM={}", album_title), file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to set new tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while setting new tag"));
+/// Load an image file as a picture of the given type. The MIME type is
+/// detected from JPEG, PNG and GIF images; images in other formats can only be
+/// loaded by giving `mime_type`, which also overrides the detected type.
+pub fn load_picture(image_path: &Path, picture_type: u32, description: &str, mime_type: Option<&str>) -> Result<Picture> {
+    let image = fs::read(image_path).map_err(Error::io(image_path))?;
+    let mut picture = match mime_type {
+        Some(mime_type) if image_info(&image).is_none() => Picture {
+            picture_type,
+            mime_type: mime_type.to_string(),
+            description: String::new(),
+            width: 0,
+            height: 0,
+            color_depth: 0,
+            colors: 0,
+            data: image,
+        },
+        _ => Picture::from_image(image, picture_type).map_err(ParseError::in_file(image_path, "image"))?,
+    };
+    if let Some(mime_type) = mime_type {
+        picture.mime_type = mime_type.to_string();
     }
-
-    Ok(())
+    picture.description = description.to_string();
+    Ok(picture)
 }
 
-fn set_mp3_album_title(file_path: &PathBuf, album_title: &str) -> Result<()> {
-    let status = Command::new("id3v2")
-        .args(["--album", album_title, file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute id3v2 command")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("id3v2 command failed"));
-    }
-
-    Ok(())
+/// Set the artist of an audio file
+pub fn set_artist(file_path: &Path, artist: &str) -> Result<()> {
+    set_field(file_path, Field::Artist, artist)
 }
 
-/// Set the cover art for an audio file using a temporary directory
-pub fn set_cover_art_with_temp(audio_path: &PathBuf, cover_path: &PathBuf, temp_dir: &PathBuf) -> Result<()> {
-    // Copy original file to temp directory
-    let backup_path = temp_dir.join(audio_path.file_name().unwrap());
-    fs::copy(audio_path, &backup_path)
-        .with_context(|| "Failed to copy original file to temp directory")?;
-    
-    println!("Original file backed up to: {}", backup_path.display());
-
-    // Use ffmpeg to copy the audio and add the cover art
-    let status = Command::new("ffmpeg")
-        .args([
-            "-y",
-            "-loglevel", "error",
-            "-i", backup_path.to_str().unwrap(),
-            "-i", cover_path.to_str().unwrap(),
-            "-map", "0:a",
-            "-map", "1:v",
-            "-c:a", "copy",
-            "-c:v", "copy",
-            "-id3v2_version", "3",
-            "-metadata:s:v", "title=Cover (front)",
-            "-metadata:s:v", "comment=Cover (front)",
-            audio_path.to_str().unwrap(),
-        ])
-        .status()
-        .with_context(|| "Failed to execute ffmpeg command")?;
-
-    if !status.success() {
-        // If ffmpeg failed, restore the original file
-        fs::copy(&backup_path, audio_path)
-            .with_context(|| "Failed to restore original file after ffmpeg error")?;
-        return Err(anyhow::anyhow!("ffmpeg command failed"));
-    }
-
-    println!("Successfully updated cover art for {}", audio_path.display());
-
-    Ok(())
+/// Set several artists on an audio file, e.g. for a collaboration. They are
+/// stored as separate values rather than one joined string.
+pub fn set_artists(file_path: &Path, artists: &[String]) -> Result<()> {
+    let mut update = TagUpdate::default();
+    update.set_values(Field::Artist, artists.to_vec());
+    update_tag(file_path, &update)
 }
 
-/// Set the song title metadata for an audio file using a temporary directory
-pub fn set_title_with_temp(file_path: &PathBuf, title: &str, temp_dir: &PathBuf) -> Result<()> {
-    // Copy original file to temp directory
-    let backup_path = temp_dir.join(file_path.file_name().unwrap());
-    fs::copy(file_path, &backup_path)
-        .with_context(|| "Failed to copy original file to temp directory")?;
-    
-    println!("Original file backed up to: {}", backup_path.display());
-
-    let extension = file_path.extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or_else(|| anyhow::anyhow!("File has no extension"))?;
-
-    let result = match extension.to_lowercase().as_str() {
-        "flac" => set_flac_title(file_path, title),
-        "mp3" => set_mp3_title(file_path, title),
-        _ => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
-    };
-
-    if let Err(e) = result {
-        // If setting title failed, restore the original file
-        fs::copy(&backup_path, file_path)
-            .with_context(|| "Failed to restore original file after error")?;
-        return Err(e);
-    }
-
-    println!("Successfully updated song title for {}", file_path.display());
-
-    Ok(())
+/// Set the album title of an audio file
+pub fn set_album_title(file_path: &Path, album_title: &str) -> Result<()> {
+    set_field(file_path, Field::Album, album_title)
 }
 
-fn set_flac_title(file_path: &PathBuf, title: &str) -> Result<()> {
-    // First remove any existing TITLE tag
-    let status = Command::new("metaflac")
-        .args(["--remove-tag", "TITLE", file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to remove existing tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while removing existing tag"));
-    }
-
-    // Then set the new TITLE tag
-    let status = Command::new("metaflac")
-        .args(["--set-tag", &format!("TITLE={}", title), file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to set new tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while setting new tag"));
-    }
-
-    Ok(())
+/// Set the song title of an audio file
+pub fn set_title(file_path: &Path, title: &str) -> Result<()> {
+    set_field(file_path, Field::Title, title)
 }
 
-fn set_mp3_title(file_path: &PathBuf, title: &str) -> Result<()> {
-    let status = Command::new("id3v2")
-        .args(["--song", title, file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute id3v2 command")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("id3v2 command failed"));
-    }
-
-    Ok(())
+/// Set the track number of an audio file
+pub fn set_track_number(file_path: &Path, track_number: u32) -> Result<()> {
+    set_field(file_path, Field::TrackNumber, &track_number.to_string())
 }
 
-/// Set the track number metadata for an audio file
-pub fn set_track_number(file_path: &PathBuf, track_number: u32) -> Result<()> {
-    let extension = file_path.extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or_else(|| anyhow::anyhow!("File has no extension"))?;
-
-    match extension.to_lowercase().as_str() {
-        "flac" => set_flac_track_number(file_path, track_number),
-        "mp3" => set_mp3_track_number(file_path, track_number),
-        _ => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
-    }
+/// Embed an image file as the front cover of an audio file
+pub fn set_cover_art(file_path: &Path, cover_path: &Path) -> Result<()> {
+    let mut update = TagUpdate::default();
+    update.pictures.push(load_cover_art(cover_path)?);
+    update_tag(file_path, &update)
 }
 
-fn set_flac_track_number(file_path: &PathBuf, track_number: u32) -> Result<()> {
-    // First remove any existing TRACKNUMBER tag
-    let status = Command::new("metaflac")
-        .args(["--remove-tag", "TRACKNUMBER", file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to remove existing tag")?;
-
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while removing existing tag"));
-    }
+fn set_field(file_path: &Path, field: Field, value: &str) -> Result<()> {
+    let mut update = TagUpdate::default();
+    update.set(field, value);
+    update_tag(file_path, &update)
+}
 
-    // Then set the new TRACKNUMBER tag
-    let status = Command::new("metaflac")
-        .args(["--set-tag", &format!("TRACKNUMBER={}", track_number), file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute metaflac command to set new tag")?;
+/// Summary of an embedded picture
+#[derive(Debug, Serialize)]
+pub struct PictureInfo {
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Size of the image data in bytes
+    pub size: usize,
+}
 
-    if !status.success() {
-        return Err(anyhow::anyhow!("metaflac command failed while setting new tag"));
+impl From<&Picture> for PictureInfo {
+    fn from(picture: &Picture) -> Self {
+        PictureInfo {
+            picture_type: picture.picture_type,
+            mime_type: picture.mime_type.clone(),
+            description: picture.description.clone(),
+            width: picture.width,
+            height: picture.height,
+            size: picture.data.len(),
+        }
     }
+}
 
-    Ok(())
+/// A native or free-form field, shown by key
+#[derive(Debug, Serialize)]
+pub struct OtherField {
+    pub key: String,
+    pub value: String,
 }
 
-fn set_mp3_track_number(file_path: &PathBuf, track_number: u32) -> Result<()> {
-    let status = Command::new("id3v2")
-        .args(["--track", &track_number.to_string(), file_path.to_str().unwrap()])
-        .status()
-        .with_context(|| "Failed to execute id3v2 command")?;
+/// The tags currently stored in an audio file
+#[derive(Debug, Serialize)]
+pub struct FileTags {
+    pub path: PathBuf,
+    /// Container and tag format, e.g. "MP3 (ID3v2.4)"
+    pub format: String,
+    pub fields: BTreeMap<Field, Vec<String>>,
+    /// Free-form fields (ID3 TXXX frames, other Vorbis comment keys)
+    pub custom: Vec<OtherField>,
+    pub pictures: Vec<PictureInfo>,
+    /// Native frames that the common tag model does not cover
+    pub other: Vec<OtherField>,
+}
 
-    if !status.success() {
-        return Err(anyhow::anyhow!("id3v2 command failed"));
-    }
+/// Read the existing tags of an audio file
+pub fn read_tags(file_path: &Path) -> Result<FileTags> {
+    let format = format_for_path(file_path)?;
+    let tag = format.read_tag(file_path)?;
+    let native = format.native_info(file_path)?;
+    let to_fields = |pairs: Vec<(String, String)>| {
+        pairs.into_iter().map(|(key, value)| OtherField { key, value }).collect()
+    };
 
-    Ok(())
+    Ok(FileTags {
+        path: file_path.to_path_buf(),
+        format: native.description,
+        pictures: tag.pictures.iter().map(PictureInfo::from).collect(),
+        fields: tag.fields,
+        custom: to_fields(tag.custom),
+        other: to_fields(native.unmapped),
+    })
 }
 
 /// Convert a FLAC file to MP3
 pub fn convert_to_mp3(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
+    input_path: &Path,
+    output_path: &Path,
     bitrate: u32,
-    temp_dir: &PathBuf
+    temp_dir: &Path
 ) -> Result<()> {
-    // Backup the original file
-    let backup_path = temp_dir.join(input_path.file_name().unwrap());
-    fs::copy(input_path, &backup_path)
-        .with_context(|| "Failed to copy original file to temp directory")?;
-
-    // Use ffmpeg to convert FLAC to MP3
-    let status = Command::new("ffmpeg")
-        .args([
-            "-y",
-            "-loglevel", "error",
-            "-i", input_path.to_str().unwrap(),
-            "-codec:a", "libmp3lame",
-            "-b:a", &format!("{}k", bitrate),
-            "-map_metadata", "0",
-            output_path.to_str().unwrap(),
-        ])
-        .status()
-        .with_context(|| "Failed to execute ffmpeg command")?;
-
-    if !status.success() {
-        // If conversion failed, restore the original file
-        fs::copy(&backup_path, input_path)
-            .with_context(|| "Failed to restore original file after ffmpeg error")?;
-        return Err(anyhow::anyhow!("ffmpeg command failed"));
-    }
-
-    Ok(())
+    let options = ConvertOptions { target: Target::Mp3, bitrate: Some(bitrate), ..ConvertOptions::default() };
+    let backup = BackupRun::in_dir(temp_dir, input_path, "convert");
+    convert::convert_file(input_path, output_path, &options, &backup)
 }
 
-/// Convert a FLAC file to MP3, preserving metadata
+/// Convert a FLAC file to MP3 next to it or in `output_dir`, preserving
+/// metadata. The input is backed up in `backup` first.
 pub fn convert_flac_to_mp3(
-    input_path: &PathBuf,
+    input_path: &Path,
     output_dir: Option<&PathBuf>,
-    bitrate: u32
+    bitrate: u32,
+    backup: &BackupRun
 ) -> Result<()> {
-    // Create a temp directory with timestamp
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let temp_dir = PathBuf::from(format!("/tmp/audio-metadata-{}", timestamp));
-    fs::create_dir(&temp_dir)
-        .with_context(|| format!("Failed to create temp directory: {}", temp_dir.display()))?;
-
-    // Determine output path
-    let output_path = if let Some(dir) = output_dir {
-        dir.join(input_path.file_stem().unwrap()).with_extension("mp3")
-    } else {
-        input_path.with_extension("mp3")
-    };
-
-    // Convert the file
-    convert_to_mp3(input_path, &output_path, bitrate, &temp_dir)?;
-
-    println!("Successfully converted {} to {}", input_path.display(), output_path.display());
-    println!("Original file is backed up at: {}", temp_dir.join(input_path.file_name().unwrap()).display());
-    println!("You can safely delete the backup when you're satisfied with the conversion.");
+    let options = ConvertOptions { target: Target::Mp3, bitrate: Some(bitrate), ..ConvertOptions::default() };
+    let output_path = convert::output_path(input_path, output_dir.map(PathBuf::as_path), Target::Mp3);
+    convert::convert_file(input_path, &output_path, &options, backup)
+}
 
-    Ok(())
+/// Read tag fields out of a file's name and parent directory names with a
+/// template such as `%n - %a - %t` (see [`FilenamePattern`])
+pub fn infer_fields_from_filename(file_path: &Path, template: &str) -> Result<Vec<(Field, String)>> {
+    FilenamePattern::parse(template)?.extract(file_path)
 }
 
 /// Infer track name from filename by removing track numbers and file extension
-pub fn infer_track_name_from_filename(file_path: &PathBuf) -> Result<String> {
+pub fn infer_track_name_from_filename(file_path: &Path) -> Result<String> {
     let filename = file_path.file_stem()
         .and_then(|stem| stem.to_str())
-        .ok_or_else(|| anyhow::anyhow!("Could not get filename"))?;
+        .ok_or_else(|| Error::InvalidInput(format!("Could not get filename: {}", file_path.display())))?;
 
     // Common patterns for track numbers at the beginning of filenames
     let patterns = [
@@ -358,7 +217,7 @@ pub fn infer_track_name_from_filename(file_path: &PathBuf) -> Result<String> {
     track_name = track_name.trim().to_string();
 
     if track_name.is_empty() {
-        return Err(anyhow::anyhow!("Could not extract track name from filename: {}", filename));
+        return Err(Error::InvalidInput(format!("Could not extract track name from filename: {}", filename)));
     }
 
     Ok(track_name)
@@ -392,5 +251,113 @@ mod tests {
                 }
             }
         }
+
+        // Templates given with --from-filename
+        type Fields = &'static [(Field, &'static str)];
+        let pattern_cases: [(&str, &str, Fields); 8] = [
+            ("03 - Artist - This Song.mp3", "%n - %a - %t",
+                &[(Field::TrackNumber, "3"), (Field::Artist, "Artist"), (Field::Title, "This Song")]),
+            ("1 - Another Song.flac", "%n - %t", &[(Field::TrackNumber, "1"), (Field::Title, "Another Song")]),
+            ("01. Third - Song.mp3", "%n. %t", &[(Field::TrackNumber, "1"), (Field::Title, "Third - Song")]),
+            ("2-05 Fourth Song.flac", "%d-%n %t",
+                &[(Field::DiscNumber, "2"), (Field::TrackNumber, "5"), (Field::Title, "Fourth Song")]),
+            ("Band/1999 - Record/07 Fifth Song.mp3", "%a/%y - %b/%n %t", &[
+                (Field::Artist, "Band"), (Field::Date, "1999"), (Field::Album, "Record"),
+                (Field::TrackNumber, "7"), (Field::Title, "Fifth Song"),
+            ]),
+            ("Music/Band/Record/08_Sixth Song.flac", "%b/%n_%t",
+                &[(Field::Album, "Record"), (Field::TrackNumber, "8"), (Field::Title, "Sixth Song")]),
+            ("[web] 09 - Seventh Song.mp3", "[%i] %n - %t", &[(Field::TrackNumber, "9"), (Field::Title, "Seventh Song")]),
+            ("100% Song.mp3", "%t%% Song", &[(Field::Title, "100")]),
+        ];
+        for (filename, template, expected) in pattern_cases {
+            let fields = infer_fields_from_filename(Path::new(filename), template)
+                .unwrap_or_else(|e| panic!("Error for filename '{}': {}", filename, e));
+            let expected: Vec<(Field, String)> = expected.iter().map(|(f, v)| (*f, v.to_string())).collect();
+            assert_eq!(fields, expected, "Failed for filename: {}", filename);
+        }
+        assert!(infer_fields_from_filename(Path::new("Song.mp3"), "%n - %t").is_err());
+        assert!(infer_fields_from_filename(Path::new("01 Song.mp3"), "%n %x").is_err());
+        assert!(infer_fields_from_filename(Path::new("01 Song.mp3"), "%t %t").is_err());
+    }
+
+    #[test]
+    fn test_read_tags() {
+        let dir = std::env::temp_dir().join(format!("audio-metadata-read-tags-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&600u32.to_be_bytes());
+        png.extend_from_slice(&400u32.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        fs::write(dir.join("cover.png"), &png).unwrap();
+
+        let mut tag = crate::tag::Tag::default();
+        tag.fields.insert(Field::Artist, vec!["A".to_string(), "B".to_string()]);
+        tag.fields.insert(Field::Title, vec!["Song".to_string()]);
+        tag.fields.insert(Field::TrackNumber, vec!["3".to_string()]);
+        tag.fields.insert(Field::TrackTotal, vec!["12".to_string()]);
+        tag.custom.push(("CATALOGNUMBER".to_string(), "XYZ-1".to_string()));
+        tag.pictures.push(load_cover_art(&dir.join("cover.png")).unwrap());
+
+        // STREAMINFO and an APPLICATION block
+        let flac = {
+            let mut bytes = b"fLaC\x00\x00\x00\x22".to_vec();
+            bytes.extend_from_slice(&[0; 34]);
+            bytes.extend_from_slice(b"\x82\x00\x00\x07abcdxyz");
+            bytes
+        };
+        for (name, contents) in [("01.mp3", b"\xFF\xFB".to_vec()), ("01.flac", flac)] {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            format_for_path(&path).unwrap().write_tag(&path, &tag, &WriteOptions::default()).unwrap();
+        }
+        // Frames and comments the common tag model does not cover
+        crate::id3::update(&dir.join("01.mp3"), |native| {
+            native.replace(crate::id3::Frame::text("TSSE", "LAME 3.100", native.version));
+        }).unwrap();
+        crate::flac::update(&dir.join("01.flac"), |native| {
+            let comments = &mut native.comments_mut().comments;
+            comments.push(("METADATA_BLOCK_PICTURE".to_string(), "AAAA".to_string()));
+            comments.push(("CAFÉ".to_string(), "x".to_string()));
+        }).unwrap();
+
+        let mp3 = read_tags(&dir.join("01.mp3")).unwrap();
+        let flac = read_tags(&dir.join("01.flac")).unwrap();
+        // Unmapped blocks and comments survive a write unless asked to be removed
+        let path = dir.join("01.flac");
+        let format = format_for_path(&path).unwrap();
+        format.write_tag(&path, &tag, &WriteOptions::default()).unwrap();
+        let kept = format.native_info(&path).unwrap().unmapped.len();
+        format.write_tag(&path, &tag, &WriteOptions { remove_unmapped: true, ..WriteOptions::default() }).unwrap();
+        let removed = format.native_info(&path).unwrap().unmapped.len();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mp3.format, "MP3 (ID3v2.4)");
+        assert_eq!(flac.format, "FLAC (Vorbis comments)");
+        for tags in [&mp3, &flac] {
+            assert_eq!(tags.fields, tag.fields);
+            let custom: Vec<_> = tags.custom.iter().map(|field| (field.key.as_str(), field.value.as_str())).collect();
+            assert_eq!(custom, [("CATALOGNUMBER", "XYZ-1")]);
+            let pictures: Vec<_> = tags.pictures.iter()
+                .map(|picture| (picture.picture_type, picture.mime_type.as_str(), picture.width, picture.height, picture.size))
+                .collect();
+            assert_eq!(pictures, [(Picture::FRONT_COVER, "image/png", 600, 400, png.len())]);
+        }
+        let other: Vec<_> = mp3.other.iter().map(|field| (field.key.as_str(), field.value.as_str())).collect();
+        assert_eq!(other, [("TSSE", "LAME 3.100")]);
+        let other: Vec<_> = flac.other.iter().map(|field| (field.key.as_str(), field.value.as_str())).collect();
+        assert_eq!(other, [
+            ("APPLICATION", "abcd: <3 bytes>"),
+            ("METADATA_BLOCK_PICTURE", "<4 bytes of base64>"),
+            ("CAFÉ", "x"),
+        ]);
+        assert_eq!((kept, removed), (3, 0));
+
+        // Fields are keyed by name and keep every value
+        let json = serde_json::to_value(&mp3).unwrap();
+        assert_eq!(json["fields"]["artist"], serde_json::json!(["A", "B"]));
+        assert_eq!(json["fields"]["track_total"], serde_json::json!(["12"]));
+        assert_eq!(json["pictures"][0]["width"], 600);
+        assert_eq!(json["other"][0], serde_json::json!({"key": "TSSE", "value": "LAME 3.100"}));
     }
 } 
\ No newline at end of file
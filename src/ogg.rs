@@ -0,0 +1,296 @@
+use std::fs;
+use std::path::Path;
+
+use crate::atomic;
+use crate::error::{Error, ParseError, ParseResult, Result};
+use crate::flac::VorbisComment;
+
+/// Header type flags of a page
+const CONTINUED: u8 = 0x01;
+const FIRST: u8 = 0x02;
+
+/// Most lacing values (and so packet segments) a page can hold
+const MAX_SEGMENTS: usize = 255;
+
+/// A page of an Ogg stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    /// Lacing values: the length of each packet segment on the page
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Page {
+    /// Parse the page at `pos`, returning it and the offset of the next page
+    fn parse(data: &[u8], pos: usize) -> ParseResult<(Page, usize)> {
+        let truncated = || ParseError(format!("page at offset {} is truncated", pos));
+        let header = data.get(pos..pos + 27).ok_or_else(truncated)?;
+        if &header[0..4] != b"OggS" || header[4] != 0 {
+            return Err(ParseError(format!("no page header at offset {}", pos)));
+        }
+        let body_start = pos + 27 + header[26] as usize;
+        let segments = data.get(pos + 27..body_start).ok_or_else(truncated)?.to_vec();
+        let body_end = body_start + segments.iter().map(|&len| len as usize).sum::<usize>();
+        let body = data.get(body_start..body_end).ok_or_else(truncated)?.to_vec();
+        let page = Page {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+            sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+            segments,
+            body,
+        };
+        Ok((page, body_end))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(27 + self.segments.len() + self.body.len());
+        out.extend_from_slice(b"OggS\0");
+        out.push(self.header_type);
+        out.extend_from_slice(&self.granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(self.segments.len() as u8);
+        out.extend_from_slice(&self.segments);
+        out.extend_from_slice(&self.body);
+        let crc = crc32(&out);
+        out[22..26].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+}
+
+/// The codec of a stream, which decides how its comment header is framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Opus,
+    Vorbis,
+}
+
+impl Codec {
+    fn detect(identification: &[u8]) -> ParseResult<Codec> {
+        if identification.starts_with(b"OpusHead") {
+            Ok(Codec::Opus)
+        } else if identification.starts_with(b"\x01vorbis") {
+            Ok(Codec::Vorbis)
+        } else {
+            Err(ParseError("not an Opus or Vorbis stream".to_string()))
+        }
+    }
+
+    /// Number of header packets before the audio packets
+    fn header_count(self) -> usize {
+        match self {
+            Codec::Opus => 2,
+            Codec::Vorbis => 3,
+        }
+    }
+
+    /// The bytes before the Vorbis comments in the comment header packet
+    fn comment_magic(self) -> &'static [u8] {
+        match self {
+            Codec::Opus => b"OpusTags",
+            Codec::Vorbis => b"\x03vorbis",
+        }
+    }
+
+    fn parse_comments(self, packet: &[u8]) -> ParseResult<VorbisComment> {
+        let magic = self.comment_magic();
+        if !packet.starts_with(magic) {
+            return Err(ParseError("the second packet is not a comment header".to_string()));
+        }
+        VorbisComment::parse(&packet[magic.len()..])
+    }
+
+    fn comment_packet(self, comments: &VorbisComment) -> Vec<u8> {
+        let mut packet = self.comment_magic().to_vec();
+        packet.extend(comments.to_bytes());
+        if self == Codec::Vorbis {
+            // Framing bit
+            packet.push(1);
+        }
+        packet
+    }
+}
+
+/// An Ogg Opus or Vorbis file, split into its header packets and the pages after them
+#[derive(Debug)]
+struct OggFile {
+    codec: Codec,
+    serial: u32,
+    /// Identification header, comment header and (for Vorbis) setup header
+    headers: Vec<Vec<u8>>,
+    /// Number of pages the headers took up when the file was read
+    header_pages: u32,
+    /// The pages after the header pages, which begin with the first audio packet
+    pages: Vec<Page>,
+}
+
+impl OggFile {
+    fn parse(data: &[u8]) -> ParseResult<OggFile> {
+        let mut pos = 0;
+        let mut serial = None;
+        let mut codec = None;
+        let mut headers: Vec<Vec<u8>> = Vec::new();
+        let mut packet = Vec::new();
+        let mut header_pages = 0;
+        // Collect the header packets, which end on a page boundary
+        while codec.is_none_or(|codec: Codec| headers.len() < codec.header_count()) {
+            if pos == data.len() {
+                return Err(ParseError("file ends inside the headers".to_string()));
+            }
+            let (page, next) = Page::parse(data, pos)?;
+            if *serial.get_or_insert(page.serial) != page.serial {
+                return Err(ParseError("streams multiplexed with the headers are not supported".to_string()));
+            }
+            let mut offset = 0;
+            for &len in &page.segments {
+                if codec.is_some_and(|codec| headers.len() == codec.header_count()) {
+                    return Err(ParseError("an audio packet shares a page with the headers".to_string()));
+                }
+                packet.extend_from_slice(&page.body[offset..offset + len as usize]);
+                offset += len as usize;
+                if len < 255 {
+                    headers.push(std::mem::take(&mut packet));
+                    if headers.len() == 1 {
+                        codec = Some(Codec::detect(&headers[0])?);
+                    }
+                }
+            }
+            pos = next;
+            header_pages += 1;
+        }
+
+        let mut pages = Vec::new();
+        while pos < data.len() {
+            let (page, next) = Page::parse(data, pos)?;
+            pages.push(page);
+            pos = next;
+        }
+        Ok(OggFile { codec: codec.unwrap(), serial: serial.unwrap(), headers, header_pages, pages })
+    }
+
+    fn comments(&self) -> ParseResult<VorbisComment> {
+        self.codec.parse_comments(&self.headers[1])
+    }
+
+    fn set_comments(&mut self, comments: &VorbisComment) {
+        self.headers[1] = self.codec.comment_packet(comments);
+    }
+
+    /// Serialize the file, laying the headers out on pages again and
+    /// renumbering the stream's pages after them
+    fn to_bytes(&self) -> Vec<u8> {
+        // The identification header has the first page to itself
+        let mut pages = paginate(&self.headers[..1], self.serial);
+        pages[0].header_type |= FIRST;
+        pages.extend(paginate(&self.headers[1..], self.serial));
+        let header_pages = pages.len() as u32;
+
+        let mut out = Vec::new();
+        for (sequence, page) in pages.iter_mut().enumerate() {
+            page.sequence = sequence as u32;
+            out.extend(page.to_bytes());
+        }
+        for page in &self.pages {
+            let mut page = page.clone();
+            if page.serial == self.serial {
+                page.sequence = page.sequence.wrapping_sub(self.header_pages).wrapping_add(header_pages);
+            }
+            out.extend(page.to_bytes());
+        }
+        out
+    }
+}
+
+/// Lay packets out on pages with a granule position of 0, as header packets
+/// are, starting a new page for the first packet
+fn paginate(packets: &[Vec<u8>], serial: u32) -> Vec<Page> {
+    let new_page = |header_type| Page { header_type, granule: 0, serial, sequence: 0, segments: Vec::new(), body: Vec::new() };
+    let mut pages = Vec::new();
+    let mut page = new_page(0);
+    for packet in packets {
+        // A packet is split into 255-byte segments and ends with a shorter (possibly empty) one
+        let mut chunks: Vec<&[u8]> = packet.chunks(255).collect();
+        if packet.len() % 255 == 0 {
+            chunks.push(&[]);
+        }
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            if page.segments.len() == MAX_SEGMENTS {
+                pages.push(std::mem::replace(&mut page, new_page(if index > 0 { CONTINUED } else { 0 })));
+            }
+            page.segments.push(chunk.len() as u8);
+            page.body.extend_from_slice(chunk);
+        }
+    }
+    pages.push(page);
+    pages
+}
+
+/// The CRC-32 of a page, computed with its checksum field zeroed
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Read the Vorbis comments of an Ogg Opus or Vorbis file, apply `edit`, and
+/// write them back. The comment header is laid out on new pages, so the pages
+/// after it are renumbered and the whole file is rewritten.
+pub(crate) fn update(path: &Path, edit: impl FnOnce(&mut VorbisComment)) -> Result<()> {
+    let data = fs::read(path).map_err(Error::io(path))?;
+    let mut file = OggFile::parse(&data).map_err(ParseError::in_file(path, "Ogg"))?;
+    let mut comments = file.comments().map_err(ParseError::in_file(path, "Ogg"))?;
+    edit(&mut comments);
+    file.set_comments(&comments);
+    atomic::write(path, &file.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_comments() {
+        let serial = 0x1234;
+        let mut comments = VorbisComment { vendor: "Lavf".to_string(), comments: vec![("ENCODER".to_string(), "x".to_string())] };
+        let mut data = Vec::new();
+        let mut pages = paginate(&[b"OpusHead\x01\x02".to_vec()], serial);
+        pages[0].header_type |= FIRST;
+        pages.extend(paginate(&[Codec::Opus.comment_packet(&comments)], serial));
+        pages.push(Page { header_type: 0x04, granule: 960, serial, sequence: 0, segments: vec![5], body: b"audio".to_vec() });
+        for (sequence, page) in pages.iter_mut().enumerate() {
+            page.sequence = sequence as u32;
+            data.extend(page.to_bytes());
+        }
+
+        // A comment header longer than a page pushes the audio page back
+        let mut file = OggFile::parse(&data).unwrap();
+        assert_eq!(file.codec, Codec::Opus);
+        assert_eq!(file.comments().unwrap(), comments);
+        comments.comments = vec![
+            ("ARTIST".to_string(), "A".to_string()),
+            ("ARTIST".to_string(), "B".to_string()),
+            ("METADATA_BLOCK_PICTURE".to_string(), "p".repeat(70_000)),
+        ];
+        file.set_comments(&comments);
+        let data = file.to_bytes();
+
+        let file = OggFile::parse(&data).unwrap();
+        assert_eq!(file.comments().unwrap(), comments);
+        let audio = &file.pages[0];
+        assert_eq!((audio.sequence, audio.granule, audio.body.as_slice()), (3, 960, b"audio".as_slice()));
+        let (first, next) = Page::parse(&data, 0).unwrap();
+        assert_eq!(first.header_type, FIRST);
+        assert_eq!(Page::parse(&data, next).unwrap().0.header_type, 0);
+    }
+}
@@ -0,0 +1,115 @@
+use regex::Regex;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::tag::Field;
+
+/// A template for reading tags out of file and directory names, such as
+/// `%n - %a - %t` or `%a/%b/%n %t`. Placeholders:
+///
+/// | Placeholder | Field |
+/// |-------------|-------|
+/// | `%a` | artist |
+/// | `%b` | album |
+/// | `%t` | title |
+/// | `%n` | track number |
+/// | `%d` | disc number |
+/// | `%y` | year |
+/// | `%i` | ignored text |
+/// | `%%` | a literal `%` |
+///
+/// Each `/` in the template matches a directory separator, so the template is
+/// matched against the file name (without its extension) and as many parent
+/// directory names as it has `/`s.
+#[derive(Debug, Clone)]
+pub struct FilenamePattern {
+    template: String,
+    regex: Regex,
+    /// The field captured by each group, in order; `None` for `%i`
+    fields: Vec<Option<Field>>,
+    components: usize,
+}
+
+impl FilenamePattern {
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |message: String| Error::InvalidInput(format!("Invalid filename pattern '{}': {}", template, message));
+        let mut regex = String::from("^");
+        let mut fields = Vec::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                regex.push_str(&regex::escape(&c.to_string()));
+                continue;
+            }
+            let letter = chars.next().ok_or_else(|| invalid("it ends with a lone %".to_string()))?;
+            let (field, group) = match letter {
+                'a' => (Some(Field::Artist), r"([^/]+?)"),
+                'b' => (Some(Field::Album), r"([^/]+?)"),
+                't' => (Some(Field::Title), r"([^/]+?)"),
+                'n' => (Some(Field::TrackNumber), r"(\d+)"),
+                'd' => (Some(Field::DiscNumber), r"(\d+)"),
+                'y' => (Some(Field::Date), r"(\d{4})"),
+                'i' => (None, r"([^/]*?)"),
+                '%' => {
+                    regex.push('%');
+                    continue;
+                }
+                other => return Err(invalid(format!("unknown placeholder %{}", other))),
+            };
+            if field.is_some() && fields.contains(&field) {
+                return Err(invalid(format!("%{} is used more than once", letter)));
+            }
+            fields.push(field);
+            regex.push_str(group);
+        }
+        if fields.iter().all(Option::is_none) {
+            return Err(invalid("it has no placeholders".to_string()));
+        }
+        regex.push('$');
+
+        Ok(FilenamePattern {
+            template: template.to_string(),
+            regex: Regex::new(&regex).map_err(|e| invalid(e.to_string()))?,
+            fields,
+            components: template.matches('/').count() + 1,
+        })
+    }
+
+    /// Extract the fields of a file from its name and parent directory names.
+    /// Track and disc numbers lose their leading zeros.
+    pub fn extract(&self, path: &Path) -> Result<Vec<(Field, String)>> {
+        let name = self.subject(path);
+        let captures = self.regex.captures(&name).ok_or_else(|| Error::InvalidInput(format!(
+            "{} does not match the filename pattern '{}'", path.display(), self.template
+        )))?;
+
+        let mut values = Vec::new();
+        for (field, capture) in self.fields.iter().zip(captures.iter().skip(1)) {
+            let (Some(field), Some(capture)) = (field, capture) else { continue };
+            let value = capture.as_str().trim();
+            let value = match field {
+                Field::TrackNumber | Field::DiscNumber => value.parse::<u32>().map_or(value.to_string(), |n| n.to_string()),
+                _ => value.to_string(),
+            };
+            if !value.is_empty() {
+                values.push((*field, value));
+            }
+        }
+        Ok(values)
+    }
+
+    /// The part of the path the template is matched against: the file stem
+    /// and enough parent directory names, joined with `/`
+    fn subject(&self, path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut parts = vec![stem.into_owned()];
+        let mut parent = path.parent();
+        while parts.len() < self.components {
+            let Some(dir) = parent.and_then(Path::file_name) else { break };
+            parts.push(dir.to_string_lossy().into_owned());
+            parent = parent.and_then(Path::parent);
+        }
+        parts.reverse();
+        parts.join("/")
+    }
+}
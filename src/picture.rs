@@ -0,0 +1,215 @@
+use crate::error::{Error, ParseError, ParseResult, Result};
+
+/// An embedded picture, as stored in FLAC PICTURE blocks and ID3 APIC frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    /// Picture type as defined by ID3v2 APIC (3 = front cover)
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub color_depth: u32,
+    /// Number of colours for indexed images, 0 otherwise
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    pub const FRONT_COVER: u32 = 3;
+
+    /// Build a picture from raw image bytes, detecting the MIME type and dimensions
+    pub(crate) fn from_image(data: Vec<u8>, picture_type: u32) -> ParseResult<Self> {
+        let info = image_info(&data)
+            .ok_or_else(|| ParseError("unrecognised image format (expected JPEG, PNG or GIF)".to_string()))?;
+        Ok(Picture {
+            picture_type,
+            mime_type: info.mime_type.to_string(),
+            description: String::new(),
+            width: info.width,
+            height: info.height,
+            color_depth: info.color_depth,
+            colors: info.colors,
+            data,
+        })
+    }
+}
+
+/// Human-readable name of an ID3v2/FLAC picture type
+pub fn picture_type_name(picture_type: u32) -> &'static str {
+    match picture_type {
+        0 => "other",
+        1 => "file icon",
+        2 => "other file icon",
+        3 => "front cover",
+        4 => "back cover",
+        5 => "leaflet page",
+        6 => "media",
+        7 => "lead artist",
+        8 => "artist",
+        9 => "conductor",
+        10 => "band",
+        11 => "composer",
+        12 => "lyricist",
+        13 => "recording location",
+        14 => "during recording",
+        15 => "during performance",
+        16 => "screen capture",
+        17 => "bright coloured fish",
+        18 => "illustration",
+        19 => "band logotype",
+        20 => "publisher logotype",
+        _ => "unknown",
+    }
+}
+
+/// Parse a picture type as given on the command line: a type name with
+/// hyphens for spaces (`front-cover`), a short alias (`front`, `back`,
+/// `booklet`, `artist`, `cd`), or its number
+pub fn parse_picture_type(name: &str) -> Result<u32> {
+    let normalized = name.trim().to_lowercase().replace([' ', '_'], "-");
+    let alias = match normalized.as_str() {
+        "front" | "cover" => Some(3),
+        "back" => Some(4),
+        "booklet" | "leaflet" => Some(5),
+        "cd" | "disc" => Some(6),
+        "icon" => Some(1),
+        _ => None,
+    };
+    alias
+        .or_else(|| normalized.parse().ok().filter(|&number| number <= 20))
+        .or_else(|| (0..=20).find(|&number| picture_type_name(number).replace(' ', "-") == normalized))
+        .ok_or_else(|| Error::InvalidInput(format!(
+            "Unknown picture type '{}': use front, back, booklet, artist, media or a number from 0 to 20", name
+        )))
+}
+
+/// Format and dimensions read from an image header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub color_depth: u32,
+    pub colors: u32,
+}
+
+/// Sniff the format and dimensions of a JPEG, PNG or GIF image without decoding it
+pub fn image_info(data: &[u8]) -> Option<ImageInfo> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_info(data)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_info(data)
+    } else if data.starts_with(b"GIF8") {
+        gif_info(data)
+    } else {
+        None
+    }
+}
+
+fn png_info(data: &[u8]) -> Option<ImageInfo> {
+    // The IHDR chunk always comes first, right after the signature
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+    let bit_depth = *data.get(24)? as u32;
+    // The bit depth comes from the file, so anything PNG does not allow is rejected
+    if ![1, 2, 4, 8, 16].contains(&bit_depth) {
+        return None;
+    }
+    let (channels, colors) = match data.get(25)? {
+        0 => (1, 0),
+        2 => (3, 0),
+        3 => (1, 1 << bit_depth),
+        4 => (2, 0),
+        6 => (4, 0),
+        _ => return None,
+    };
+    Some(ImageInfo { mime_type: "image/png", width, height, color_depth: bit_depth * channels, colors })
+}
+
+fn jpeg_info(data: &[u8]) -> Option<ImageInfo> {
+    let mut pos = 2;
+    loop {
+        // Skip fill bytes before the marker
+        while *data.get(pos)? == 0xFF && *data.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        // SOF0..SOF15 carry the frame dimensions; C4, C8 and CC are other segments
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let precision = *data.get(pos + 4)? as u32;
+            let height = u16::from_be_bytes([*data.get(pos + 5)?, *data.get(pos + 6)?]) as u32;
+            let width = u16::from_be_bytes([*data.get(pos + 7)?, *data.get(pos + 8)?]) as u32;
+            let components = *data.get(pos + 9)? as u32;
+            return Some(ImageInfo {
+                mime_type: "image/jpeg",
+                width,
+                height,
+                color_depth: precision * components,
+                colors: 0,
+            });
+        }
+        pos += 2 + len;
+    }
+}
+
+fn gif_info(data: &[u8]) -> Option<ImageInfo> {
+    let width = u16::from_le_bytes([*data.get(6)?, *data.get(7)?]) as u32;
+    let height = u16::from_le_bytes([*data.get(8)?, *data.get(9)?]) as u32;
+    let packed = *data.get(10)?;
+    let bits = (packed & 0x07) as u32 + 1;
+    Some(ImageInfo { mime_type: "image/gif", width, height, color_depth: bits, colors: 1 << bits })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_info() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&600u32.to_be_bytes());
+        png.extend_from_slice(&400u32.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let info = image_info(&png).unwrap();
+        assert_eq!((info.mime_type, info.width, info.height, info.color_depth), ("image/png", 600, 400, 32));
+
+        // SOI, an APP0 segment, then a baseline SOF0 for a 3-component 500x300 image
+        let jpeg = [
+            0xFF, 0xD8,
+            0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00,
+            0xFF, 0xC0, 0x00, 0x11, 0x08, 0x01, 0x2C, 0x01, 0xF4, 0x03,
+        ];
+        let info = image_info(&jpeg).unwrap();
+        assert_eq!((info.mime_type, info.width, info.height, info.color_depth), ("image/jpeg", 500, 300, 24));
+
+        assert!(image_info(b"not an image").is_none());
+
+        // An indexed PNG with an impossible bit depth must not overflow the colour count
+        let mut indexed = png[..24].to_vec();
+        indexed.extend_from_slice(&[64, 3, 0, 0, 0]);
+        assert!(image_info(&indexed).is_none());
+        indexed[24] = 4;
+        assert_eq!(image_info(&indexed).unwrap().colors, 16);
+    }
+
+    #[test]
+    fn test_parse_picture_type() {
+        assert_eq!(parse_picture_type("front").unwrap(), Picture::FRONT_COVER);
+        assert_eq!(parse_picture_type("Back-Cover").unwrap(), 4);
+        assert_eq!(parse_picture_type("booklet").unwrap(), 5);
+        assert_eq!(parse_picture_type("lead artist").unwrap(), 7);
+        assert_eq!(parse_picture_type("artist").unwrap(), 8);
+        assert_eq!(parse_picture_type("18").unwrap(), 18);
+        assert!(parse_picture_type("21").is_err());
+        assert!(parse_picture_type("poster").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Number of worker threads to use when none is given: one per CPU
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Run `task` on every item using up to `jobs` worker threads. `report` is
+/// called on the calling thread with each item's result in item order, as soon
+/// as that item and all earlier ones have finished, so progress output reads
+/// the same however many workers there are.
+pub(crate) fn for_each_ordered<T, R>(
+    items: &[T],
+    jobs: usize,
+    task: impl Fn(&T) -> R + Sync,
+    mut report: impl FnMut(&T, R),
+) where
+    T: Sync,
+    R: Send,
+{
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let sender = sender.clone();
+            let (next, task) = (&next, &task);
+            scope.spawn(move || {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else { break };
+                    if sender.send((index, task(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Hold results that finish early until every earlier one has been reported
+        let mut pending = BTreeMap::new();
+        let mut reported = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&reported) {
+                report(&items[reported], result);
+                reported += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_for_each_ordered_reports_in_item_order() {
+        let items: Vec<u64> = (0..20).collect();
+        let mut reported = Vec::new();
+        for_each_ordered(&items, 4, |&item| {
+            // Later items finish first
+            thread::sleep(Duration::from_millis(20 - item));
+            item * 10
+        }, |&item, result| reported.push((item, result)));
+
+        assert_eq!(reported, items.iter().map(|&item| (item, item * 10)).collect::<Vec<_>>());
+    }
+}
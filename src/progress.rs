@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+
+/// Something that happened while processing files, passed to the caller's
+/// progress callback as soon as it happens and in file order. The library
+/// prints nothing itself.
+#[derive(Debug)]
+pub enum Progress {
+    /// A file was processed: a line describing what was done, or why it failed
+    File { path: PathBuf, result: Result<String> },
+    /// A problem that does not stop the run, e.g. a directory without a cover image
+    Warning(String),
+}
+
+/// Counts the files of a run that failed while passing their outcomes on
+pub(crate) struct Tally<'a> {
+    progress: &'a mut dyn FnMut(Progress),
+    total: usize,
+    failed: usize,
+}
+
+impl<'a> Tally<'a> {
+    pub(crate) fn new(progress: &'a mut dyn FnMut(Progress)) -> Self {
+        Tally { progress, total: 0, failed: 0 }
+    }
+
+    pub(crate) fn file(&mut self, path: PathBuf, result: Result<String>) {
+        self.total += 1;
+        self.failed += usize::from(result.is_err());
+        (self.progress)(Progress::File { path, result });
+    }
+
+    pub(crate) fn warning(&mut self, message: String) {
+        (self.progress)(Progress::Warning(message));
+    }
+
+    /// `Error::PartialFailure` if any file failed
+    pub(crate) fn finish(self) -> Result<()> {
+        match self.failed {
+            0 => Ok(()),
+            failed => Err(Error::PartialFailure { failed, total: self.total }),
+        }
+    }
+}
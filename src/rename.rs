@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::atomic;
+use crate::backup::BackupRun;
+use crate::error::{Error, Result};
+use crate::file_ops::SUPPORTED_EXTENSIONS;
+use crate::format::format_for_path;
+use crate::progress::{Progress, Tally};
+use crate::tag::{Field, Tag};
+use crate::walk::{WalkOptions, collect_file_groups};
+
+/// A template for naming files after their tags, such as
+/// `{track:02} - {title}` or `{artist}/{album} ({year})/{track:02} - {title}`.
+/// Placeholders are `{artist}`, `{albumartist}`, `{album}`, `{title}`,
+/// `{track}`, `{disc}`, `{year}`, `{date}` and `{genre}`; `{track:02}` pads a number with zeros to two digits.
+/// `{{` and `}}` are literal braces, and `/` starts a new directory.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field { placeholder: Placeholder, name: String, width: usize },
+    Separator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Field(Field),
+    /// The first four characters of the date
+    Year,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |message: String| Error::InvalidInput(format!("Invalid name template '{}': {}", template, message));
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => literal.push(chars.next().unwrap()),
+                '}' if chars.peek() == Some(&'}') => literal.push(chars.next().unwrap()),
+                '}' => return Err(invalid("unmatched }".to_string())),
+                '/' => {
+                    parts.extend((!literal.is_empty()).then(|| Part::Literal(std::mem::take(&mut literal))));
+                    parts.push(Part::Separator);
+                }
+                '{' => {
+                    parts.extend((!literal.is_empty()).then(|| Part::Literal(std::mem::take(&mut literal))));
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(invalid("unclosed {".to_string())),
+                        }
+                    }
+                    let (name, width) = spec.split_once(':').unwrap_or((&spec, ""));
+                    let placeholder = parse_placeholder(name)
+                        .ok_or_else(|| invalid(format!("unknown placeholder {{{}}}", name)))?;
+                    let width = if width.is_empty() {
+                        0
+                    } else {
+                        width.parse().map_err(|_| invalid(format!("invalid width in {{{}}}", spec)))?
+                    };
+                    parts.push(Part::Field { placeholder, name: name.to_string(), width });
+                }
+                _ => literal.push(c),
+            }
+        }
+        parts.extend((!literal.is_empty()).then_some(Part::Literal(literal)));
+        if !parts.iter().any(|part| matches!(part, Part::Field { .. })) {
+            return Err(invalid("it has no placeholders".to_string()));
+        }
+        if matches!(parts.first(), Some(Part::Separator)) || matches!(parts.last(), Some(Part::Separator)) {
+            return Err(invalid("it must not start or end with /".to_string()));
+        }
+        Ok(NameTemplate { template: template.to_string(), parts })
+    }
+
+    /// Whether the template moves files into subdirectories
+    pub fn has_directories(&self) -> bool {
+        self.parts.contains(&Part::Separator)
+    }
+
+    /// The relative path (without an extension) for a file with this tag.
+    /// Every directory and file name is made safe for the filesystem.
+    pub fn render(&self, tag: &Tag) -> Result<PathBuf> {
+        let mut path = PathBuf::new();
+        let mut component = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => component.push_str(text),
+                Part::Separator => path.push(sanitize(&std::mem::take(&mut component))),
+                Part::Field { placeholder, name, width } => {
+                    let value = placeholder_value(tag, *placeholder).ok_or_else(|| Error::InvalidInput(format!(
+                        "no {} tag to fill in {{{}}} of '{}'", name, name, self.template
+                    )))?;
+                    // Slashes in values must not create directories
+                    let value = value.replace(['/', '\\'], "-");
+                    let padded = if value.chars().all(|c| c.is_ascii_digit()) {
+                        format!("{:0>width$}", value, width = *width)
+                    } else {
+                        value
+                    };
+                    component.push_str(&padded);
+                }
+            }
+        }
+        path.push(sanitize(&component));
+        Ok(path)
+    }
+}
+
+fn parse_placeholder(name: &str) -> Option<Placeholder> {
+    let field = match name {
+        "artist" => Field::Artist,
+        "albumartist" => Field::AlbumArtist,
+        "album" => Field::Album,
+        "title" => Field::Title,
+        "track" => Field::TrackNumber,
+        "disc" => Field::DiscNumber,
+        "date" => Field::Date,
+        "genre" => Field::Genre,
+        "year" => return Some(Placeholder::Year),
+        _ => return None,
+    };
+    Some(Placeholder::Field(field))
+}
+
+/// The text a placeholder stands for. Numbers in "n/total" form lose the total.
+fn placeholder_value(tag: &Tag, placeholder: Placeholder) -> Option<String> {
+    let field = match placeholder {
+        Placeholder::Field(field) => field,
+        Placeholder::Year => Field::Date,
+    };
+    let value = tag.fields.get(&field)?.first()?.trim();
+    let value = match placeholder {
+        Placeholder::Field(Field::TrackNumber | Field::DiscNumber) => value.split('/').next().unwrap_or(value).trim(),
+        Placeholder::Year => value.get(..4).unwrap_or(value),
+        _ => value,
+    };
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Make a directory or file name safe on common filesystems: characters that
+/// are reserved on Windows or are control characters become `_`, and leading
+/// and trailing spaces and trailing dots are removed
+pub fn sanitize(name: &str) -> String {
+    let replaced: String = name.chars()
+        .map(|c| if c.is_control() || r#"<>:"/\|?*"#.contains(c) { '_' } else { c })
+        .collect();
+    let trimmed = replaced.trim().trim_end_matches('.').trim_end();
+    match trimmed {
+        "" | "." | ".." => "_".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// How `rename_files` names files
+#[derive(Debug, Default)]
+pub struct RenameOptions {
+    /// The name template, e.g. `{track:02} - {title}`
+    pub template: String,
+    /// Directory that templates with `/` are applied in (default: the
+    /// directory being renamed, or the file's own directory)
+    pub output_dir: Option<PathBuf>,
+    /// Print the new names instead of renaming
+    pub dry_run: bool,
+}
+
+/// Rename an audio file, or the audio files in a directory, after their tags.
+/// A file whose new name is taken gets a ` (2)`, ` (3)`, ... suffix. Every
+/// move is recorded in `backup` first so it can be undone with `restore`, and
+/// each file's outcome is passed to `progress`. A single file's error is
+/// returned as is; for a directory, `Error::PartialFailure` if any file failed.
+pub fn rename_files(
+    path: &Path,
+    options: &RenameOptions,
+    walk: &WalkOptions,
+    backup: &BackupRun,
+    progress: &mut dyn FnMut(Progress)
+) -> Result<()> {
+    let template = NameTemplate::parse(&options.template)?;
+    let files: Vec<PathBuf> = if path.is_dir() {
+        collect_file_groups(path, &SUPPORTED_EXTENSIONS, walk)?.into_iter().flatten().collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let root = if path.is_dir() { path } else { path.parent().unwrap_or(Path::new("")) };
+
+    let mut claimed = HashSet::new();
+    let mut tally = Tally::new(progress);
+    for file in &files {
+        let base = match &options.output_dir {
+            Some(dir) => dir.as_path(),
+            None if template.has_directories() => root,
+            None => file.parent().unwrap_or(Path::new("")),
+        };
+        match rename_file(file, &template, base, options.dry_run, &mut claimed, backup) {
+            Err(e) if !path.is_dir() => return Err(e),
+            result => tally.file(file.clone(), result),
+        }
+    }
+
+    tally.finish()
+}
+
+/// Rename one file, returning a line of progress output
+fn rename_file(
+    file: &Path,
+    template: &NameTemplate,
+    base: &Path,
+    dry_run: bool,
+    claimed: &mut HashSet<PathBuf>,
+    backup: &BackupRun
+) -> Result<String> {
+    let tag = format_for_path(file)?.read_tag(file)?;
+    let relative = template.render(&tag)?;
+    // Appended rather than set with `with_extension`, as titles may contain dots
+    let extension = file.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    let mut target = base.join(&relative).into_os_string();
+    target.push(format!(".{}", extension));
+    let target = PathBuf::from(target);
+    // A file already named e.g. "Title (2)" because "Title" was taken keeps its name
+    let target = free_path(&target, file, claimed);
+    claimed.insert(target.clone());
+    if target == file {
+        return Ok(format!("Nothing to change for {}", file.display()));
+    }
+
+    if dry_run {
+        return Ok(format!("Would rename {} to {}", file.display(), target.display()));
+    }
+    backup.record_rename(file, &target)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(Error::io(parent))?;
+    }
+    atomic::rename(file, &target)?;
+    Ok(format!("Renamed {} to {}", file.display(), target.display()))
+}
+
+/// `target`, or `target` with a ` (2)`, ` (3)`, ... suffix if it belongs to
+/// another file or was already chosen for one in this run
+fn free_path(target: &Path, file: &Path, claimed: &HashSet<PathBuf>) -> PathBuf {
+    let taken = |candidate: &Path| {
+        claimed.contains(candidate) || (candidate.exists() && !same_file(candidate, file))
+    };
+    if !taken(target) {
+        return target.to_path_buf();
+    }
+    let stem = target.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = target.extension().unwrap_or_default().to_string_lossy().into_owned();
+    (2..)
+        .map(|n| target.with_file_name(format!("{} ({}).{}", stem, n, extension)))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// Whether two paths are the same file, e.g. differing only in case on a
+/// case-insensitive filesystem
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let mut tag = Tag::default();
+        for (field, value) in [
+            (Field::Artist, "AC/DC"),
+            (Field::Album, "Back in Black?"),
+            (Field::Title, "Hells Bells"),
+            (Field::TrackNumber, "1/10"),
+            (Field::Date, "1980-07-25"),
+        ] {
+            tag.fields.insert(field, vec![value.to_string()]);
+        }
+
+        let render = |template: &str| NameTemplate::parse(template).unwrap().render(&tag).unwrap();
+        assert_eq!(render("{track:02} - {title}"), PathBuf::from("01 - Hells Bells"));
+        assert_eq!(
+            render("{artist}/{album} ({year})/{track:02} - {title}"),
+            PathBuf::from("AC-DC/Back in Black_ (1980)/01 - Hells Bells")
+        );
+        assert_eq!(render("{{{track}}} {title}."), PathBuf::from("{1} Hells Bells"));
+
+        assert!(NameTemplate::parse("{track:02} - {titel}").is_err());
+        assert!(NameTemplate::parse("no placeholders").is_err());
+        assert!(NameTemplate::parse("{track} - {title").is_err());
+        assert!(NameTemplate::parse("{disc}-{track}").unwrap().render(&tag).is_err());
+        assert_eq!(sanitize(" .. "), "_");
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{Result, bail};
+use std::path::{Path, PathBuf};
+
+use audio_metadata::backup::{self, RunInfo};
+use audio_metadata::{Restored, list_runs, restore_entry};
+
+/// List the backup runs, or restore the files of one run (all of them, or
+/// only those given in `files`)
+pub fn restore(root: &Path, run: Option<&str>, files: &[String]) -> Result<()> {
+    let runs = list_runs(root)?;
+
+    let Some(run) = run else {
+        if runs.is_empty() {
+            println!("No backups found in {}", root.display());
+        }
+        for run in &runs {
+            print_run(run);
+        }
+        return Ok(());
+    };
+
+    let run = find_run(root, runs, run)?;
+    let selected: Vec<PathBuf> = files.iter()
+        .map(|file| std::path::absolute(file).unwrap_or_else(|_| PathBuf::from(file)))
+        .collect();
+    let entries: Vec<_> = run.entries.iter()
+        .filter(|entry| selected.is_empty() || selected.iter().any(|file| {
+            matches(file, &entry.original) || entry.renamed_to.as_deref().is_some_and(|renamed| matches(file, renamed))
+        }))
+        .collect();
+    if entries.is_empty() {
+        bail!("None of the requested files are in backup {}", run.dir.display());
+    }
+
+    // Undo in reverse order, so a file renamed onto a name another file had just given up moves out first
+    let mut error_count = 0;
+    for entry in entries.into_iter().rev() {
+        match restore_entry(&run.dir, entry) {
+            Ok(Restored::Restored) => println!("Restored {}", entry.original.display()),
+            Ok(Restored::Unchanged) => println!("Unchanged {} (already matches the backup)", entry.original.display()),
+            Err(e) => {
+                eprintln!("Error restoring {}: {}", entry.original.display(), e);
+                error_count += 1;
+            }
+        }
+    }
+
+    if error_count > 0 {
+        println!("\nCompleted with {} errors. Check the messages above for details.", error_count);
+    }
+
+    Ok(())
+}
+
+/// Find a run by directory name, by path, or "latest" for the most recent one
+fn find_run(root: &Path, runs: Vec<RunInfo>, run: &str) -> Result<RunInfo> {
+    if run == "latest" {
+        return match runs.into_iter().last() {
+            Some(run) => Ok(run),
+            None => bail!("No backups found in {}", root.display()),
+        };
+    }
+
+    let dir = if Path::new(run).is_absolute() { PathBuf::from(run) } else { root.join(run) };
+    if let Some(found) = runs.into_iter().find(|candidate| candidate.dir == dir) {
+        return Ok(found);
+    }
+    // Runs outside the default location can still be restored by path
+    Ok(backup::read_run(dir)?)
+}
+
+/// Compare paths, resolving symlinks where the file still exists
+fn matches(file: &Path, original: &Path) -> bool {
+    file == original || std::fs::canonicalize(file).is_ok_and(|file| file == original)
+}
+
+fn print_run(run: &RunInfo) {
+    let name = run.dir.file_name().unwrap().to_string_lossy();
+    let time = run.entries.first().map_or_else(|| "-".to_string(), |entry| format_time(entry.time));
+    let mut operations: Vec<&str> = run.entries.iter().map(|entry| entry.operation.as_str()).collect();
+    operations.dedup();
+    println!("{}  {}  {}  {} files", name, time, operations.join(", "), run.entries.len());
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time
+pub(crate) fn format_time(secs: u64) -> String {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, secs / 3600, secs / 60 % 60, secs % 60
+    )
+}
@@ -0,0 +1,119 @@
+use anyhow::Result;
+use std::path::Path;
+
+use audio_metadata::picture::picture_type_name;
+use audio_metadata::{Field, FileTags, collect_audio_files, read_tags};
+
+/// Print the existing tags of a file, or of every supported file in a directory
+pub fn show(path: &Path, json: bool) -> Result<()> {
+    let mut error_count = 0;
+    let tags = collect_tags(path, &mut error_count)?;
+
+    if json {
+        // Always an array, even for a single file, so consumers see one shape
+        println!("{}", serde_json::to_string_pretty(&tags)?);
+    } else {
+        for (index, file_tags) in tags.iter().enumerate() {
+            if index > 0 {
+                println!();
+            }
+            print_table(file_tags);
+        }
+    }
+
+    if error_count > 0 {
+        // Keep stdout clean for --json consumers
+        eprintln!("\nCompleted with {} errors. Check the messages above for details.", error_count);
+    }
+
+    Ok(())
+}
+
+/// Read the tags of a file, or of every supported file in a directory,
+/// counting the files that could not be read
+fn collect_tags(path: &Path, error_count: &mut usize) -> Result<Vec<FileTags>> {
+    if !path.is_dir() {
+        return Ok(vec![read_tags(path)?]);
+    }
+    let mut tags = Vec::new();
+    for file in collect_audio_files(path)? {
+        match read_tags(&file) {
+            Ok(file_tags) => tags.push(file_tags),
+            Err(e) => {
+                eprintln!("Error reading tags from {}: {}", file.display(), e);
+                *error_count += 1;
+            }
+        }
+    }
+    Ok(tags)
+}
+
+fn print_table(tags: &FileTags) {
+    let mut rows: Vec<(String, String)> = vec![("Format".to_string(), tags.format.clone())];
+    for field in Field::ALL {
+        let value = match tags.fields.get(&field) {
+            // Keep multi-line values such as lyrics on one row
+            Some(values) => values.join("; ").lines().collect::<Vec<_>>().join(" / "),
+            None => "-".to_string(),
+        };
+        rows.push((field.label().to_string(), value));
+    }
+
+    if tags.pictures.is_empty() {
+        rows.push(("Cover".to_string(), "none".to_string()));
+    }
+    for picture in &tags.pictures {
+        let dimensions = if picture.width > 0 && picture.height > 0 {
+            format!("{}x{}", picture.width, picture.height)
+        } else {
+            "unknown size".to_string()
+        };
+        let mut value = format!(
+            "{}, {}, {}, {:.1} KB",
+            picture_type_name(picture.picture_type),
+            picture.mime_type,
+            dimensions,
+            picture.size as f64 / 1024.0
+        );
+        if !picture.description.is_empty() {
+            value = format!("{}, '{}'", value, picture.description);
+        }
+        rows.push(("Cover".to_string(), value));
+    }
+
+    for field in tags.custom.iter().chain(&tags.other) {
+        rows.push((field.key.clone(), field.value.clone()));
+    }
+
+    let width = rows.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+    println!("{}", tags.path.display());
+    for (key, value) in rows {
+        println!("  {:<width$}  {}", key, value, width = width);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_json_is_array() {
+        let dir = std::env::temp_dir().join(format!("audio-metadata-show-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("01.mp3"), b"\xFF\xFB").unwrap();
+        fs::write(dir.join("02.mp3"), b"\xFF\xFB").unwrap();
+        let json = |path: &Path| {
+            let mut error_count = 0;
+            let value = serde_json::to_value(collect_tags(path, &mut error_count).unwrap()).unwrap();
+            assert_eq!(error_count, 0);
+            value.as_array().map(Vec::len)
+        };
+
+        assert_eq!(json(&dir.join("01.mp3")), Some(1));
+        assert_eq!(json(&dir), Some(2));
+        assert_eq!(json(&dir.join("empty")), Some(0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
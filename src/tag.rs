@@ -0,0 +1,499 @@
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::error::{Error, Result};
+use crate::picture::{Picture, picture_type_name};
+
+/// The common tag fields, independent of the container format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    TrackNumber,
+    TrackTotal,
+    DiscNumber,
+    DiscTotal,
+    Date,
+    Genre,
+    Composer,
+    Performer,
+    Comment,
+    Bpm,
+    Isrc,
+    Copyright,
+    /// Unsynchronised lyrics
+    Lyrics,
+}
+
+/// Something `--clear` and `--keep` can name: a field, a custom tag key, or
+/// the embedded pictures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagItem {
+    Field(Field),
+    /// An upper-cased custom tag key
+    Custom(String),
+    Pictures,
+}
+
+impl TagItem {
+    fn matches_custom(&self, key: &str) -> bool {
+        matches!(self, TagItem::Custom(custom) if custom.eq_ignore_ascii_case(key))
+    }
+}
+
+/// Parse a field name as used on the command line: `pictures` (or `cover`),
+/// a field such as `album-artist` or `track-total`, a Vorbis comment name
+/// such as `ALBUMARTIST`, or else a custom tag key
+pub fn parse_tag_item(name: &str) -> Result<TagItem> {
+    let normalized = name.trim().to_lowercase().replace('_', "-");
+    if matches!(normalized.as_str(), "pictures" | "picture" | "cover" | "covers") {
+        return Ok(TagItem::Pictures);
+    }
+    let field = Field::ALL.into_iter()
+        .find(|field| field.label().to_lowercase().replace(' ', "-") == normalized)
+        .or_else(|| Field::from_vorbis_key(name.trim()));
+    match field {
+        Some(field) => Ok(TagItem::Field(field)),
+        None => parse_tag_key(name.trim()).map(TagItem::Custom),
+    }
+}
+
+/// Where a field is kept in an ID3v2 tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Id3Frame {
+    /// A text frame, e.g. TPE1
+    Text(&'static str),
+    /// A COMM or USLT frame with an empty description
+    Described(&'static str),
+    /// A TXXX frame with this description, for fields ID3 has no frame for
+    UserText(&'static str),
+}
+
+/// Custom tag keys that ID3v2 tags store in TXXX frames under another
+/// description, following MusicBrainz Picard. Other keys are their own description.
+const ID3_DESCRIPTIONS: [(&str, &str); 10] = [
+    ("MUSICBRAINZ_RELEASETRACKID", "MusicBrainz Release Track Id"),
+    ("MUSICBRAINZ_ALBUMID", "MusicBrainz Album Id"),
+    ("MUSICBRAINZ_ARTISTID", "MusicBrainz Artist Id"),
+    ("MUSICBRAINZ_ALBUMARTISTID", "MusicBrainz Album Artist Id"),
+    ("MUSICBRAINZ_RELEASEGROUPID", "MusicBrainz Release Group Id"),
+    ("MUSICBRAINZ_WORKID", "MusicBrainz Work Id"),
+    ("RELEASETYPE", "MusicBrainz Album Type"),
+    ("RELEASESTATUS", "MusicBrainz Album Status"),
+    ("RELEASECOUNTRY", "MusicBrainz Album Release Country"),
+    ("ACOUSTID_ID", "Acoustid Id"),
+];
+
+/// The TXXX description holding a custom tag key in ID3v2 tags
+pub fn id3_description(key: &str) -> &str {
+    ID3_DESCRIPTIONS.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(key))
+        .map_or(key, |(_, description)| description)
+}
+
+/// The custom tag key for a TXXX description, the reverse of `id3_description`
+pub fn key_for_id3_description(description: &str) -> &str {
+    ID3_DESCRIPTIONS.iter()
+        .find(|(_, known)| known.eq_ignore_ascii_case(description))
+        .map_or(description, |(key, _)| key)
+}
+
+impl Field {
+    pub const ALL: [Field; 17] = [
+        Field::Artist, Field::AlbumArtist, Field::Album, Field::Title, Field::TrackNumber, Field::TrackTotal,
+        Field::DiscNumber, Field::DiscTotal, Field::Date, Field::Genre, Field::Composer, Field::Performer,
+        Field::Comment, Field::Bpm, Field::Isrc, Field::Copyright, Field::Lyrics,
+    ];
+
+    /// The total that goes with a track or disc number
+    pub fn total(self) -> Option<Field> {
+        match self {
+            Field::TrackNumber => Some(Field::TrackTotal),
+            Field::DiscNumber => Some(Field::DiscTotal),
+            _ => None,
+        }
+    }
+
+    pub fn is_total(self) -> bool {
+        matches!(self, Field::TrackTotal | Field::DiscTotal)
+    }
+
+    /// Label used in human-readable output
+    pub fn label(self) -> &'static str {
+        match self {
+            Field::Artist => "Artist",
+            Field::AlbumArtist => "Album artist",
+            Field::Album => "Album",
+            Field::Title => "Title",
+            Field::TrackNumber => "Track",
+            Field::TrackTotal => "Track total",
+            Field::DiscNumber => "Disc",
+            Field::DiscTotal => "Disc total",
+            Field::Date => "Date",
+            Field::Genre => "Genre",
+            Field::Composer => "Composer",
+            Field::Performer => "Performer",
+            Field::Comment => "Comment",
+            Field::Bpm => "BPM",
+            Field::Isrc => "ISRC",
+            Field::Copyright => "Copyright",
+            Field::Lyrics => "Lyrics",
+        }
+    }
+
+    /// The frame holding this field in an ID3v2 tag of the given major
+    /// version (3 or 4). Totals share the frame of their number as "n/N".
+    pub fn id3_frame(self, version: u8) -> Id3Frame {
+        let id = match self {
+            Field::Artist => "TPE1",
+            Field::AlbumArtist => "TPE2",
+            Field::Album => "TALB",
+            Field::Title => "TIT2",
+            Field::TrackNumber | Field::TrackTotal => "TRCK",
+            Field::DiscNumber | Field::DiscTotal => "TPOS",
+            // ID3v2.3 only has a year frame; v2.4 replaced it with a timestamp
+            Field::Date if version == 3 => "TYER",
+            Field::Date => "TDRC",
+            Field::Genre => "TCON",
+            Field::Composer => "TCOM",
+            // TMCL (v2.4 only) pairs instruments with names, so plain performer
+            // names go in a TXXX frame, as other taggers do
+            Field::Performer => return Id3Frame::UserText("PERFORMER"),
+            Field::Comment => return Id3Frame::Described("COMM"),
+            Field::Bpm => "TBPM",
+            Field::Isrc => "TSRC",
+            Field::Copyright => "TCOP",
+            Field::Lyrics => return Id3Frame::Described("USLT"),
+        };
+        Id3Frame::Text(id)
+    }
+
+    /// The field a Vorbis comment key (or a custom tag key) stands for,
+    /// including other names some taggers use. Case is ignored.
+    pub fn from_vorbis_key(key: &str) -> Option<Field> {
+        let alias = match key.to_uppercase().as_str() {
+            "TOTALTRACKS" => Some(Field::TrackTotal),
+            "TOTALDISCS" => Some(Field::DiscTotal),
+            "ALBUM ARTIST" => Some(Field::AlbumArtist),
+            "UNSYNCEDLYRICS" => Some(Field::Lyrics),
+            _ => None,
+        };
+        Field::ALL.into_iter().find(|field| field.vorbis_key().eq_ignore_ascii_case(key)).or(alias)
+    }
+
+    /// Vorbis comment field name holding this field
+    pub fn vorbis_key(self) -> &'static str {
+        match self {
+            Field::Artist => "ARTIST",
+            Field::AlbumArtist => "ALBUMARTIST",
+            Field::Album => "ALBUM",
+            Field::Title => "TITLE",
+            Field::TrackNumber => "TRACKNUMBER",
+            Field::TrackTotal => "TRACKTOTAL",
+            Field::DiscNumber => "DISCNUMBER",
+            Field::DiscTotal => "DISCTOTAL",
+            Field::Date => "DATE",
+            Field::Genre => "GENRE",
+            Field::Composer => "COMPOSER",
+            Field::Performer => "PERFORMER",
+            Field::Comment => "COMMENT",
+            Field::Bpm => "BPM",
+            Field::Isrc => "ISRC",
+            Field::Copyright => "COPYRIGHT",
+            Field::Lyrics => "LYRICS",
+        }
+    }
+}
+
+/// A format-agnostic tag. Every field may hold several values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tag {
+    pub fields: BTreeMap<Field, Vec<String>>,
+    /// Free-form fields (ID3 TXXX frames, other Vorbis comment keys) in file order
+    pub custom: Vec<(String, String)>,
+    pub pictures: Vec<Picture>,
+}
+
+impl Tag {
+    /// Split track and disc numbers written as "n/N" into the number and its
+    /// total, unless the total is also set on its own
+    pub(crate) fn split_numbers(&mut self) {
+        for field in [Field::TrackNumber, Field::DiscNumber] {
+            let Some(value) = self.fields.get(&field).and_then(|values| values.first()) else { continue };
+            let Some((number, total)) = value.split_once('/') else { continue };
+            let (number, total) = (number.trim().to_string(), total.trim().to_string());
+            let total_field = field.total().unwrap();
+            if !total.is_empty() && !self.fields.contains_key(&total_field) {
+                self.fields.insert(total_field, vec![total]);
+            }
+            if number.is_empty() {
+                self.fields.remove(&field);
+            } else {
+                self.fields.get_mut(&field).unwrap()[0] = number;
+            }
+        }
+    }
+
+    /// A track or disc number with its total appended as "n/N", the form ID3
+    /// stores them in. A total without a number cannot be stored that way.
+    pub(crate) fn joined_number(&self, field: Field) -> Option<Vec<String>> {
+        let mut values = self.fields.get(&field)?.clone();
+        let total = field.total().and_then(|total| self.fields.get(&total)).and_then(|values| values.first());
+        if let (Some(number), Some(total)) = (values.first_mut(), total) {
+            *number = format!("{}/{}", number, total);
+        }
+        Some(values)
+    }
+
+    /// Add a picture, replacing any existing picture of the same type
+    pub fn set_picture(&mut self, picture: Picture) {
+        self.pictures.retain(|p| p.picture_type != picture.picture_type);
+        self.pictures.push(picture);
+    }
+
+    /// The values of a custom tag, matching the key case-insensitively
+    pub fn custom_values(&self, key: &str) -> Vec<String> {
+        self.custom.iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    /// Describe how `after` differs from this tag, one line per changed field
+    /// or picture, e.g. `Album: 'Old' -> 'New'`
+    pub fn diff(&self, after: &Tag) -> Vec<String> {
+        let mut lines = Vec::new();
+        let fields: BTreeSet<Field> = self.fields.keys().chain(after.fields.keys()).copied().collect();
+        for field in fields {
+            let (old, new) = (self.fields.get(&field), after.fields.get(&field));
+            if old != new {
+                lines.push(format!("{}: {} -> {}", field.label(), describe_values(old), describe_values(new)));
+            }
+        }
+
+        let keys: BTreeSet<String> = self.custom.iter().chain(&after.custom).map(|(key, _)| key.to_uppercase()).collect();
+        for key in keys {
+            let (old, new) = (self.custom_values(&key), after.custom_values(&key));
+            if old != new {
+                let describe = |values: Vec<String>| describe_values((!values.is_empty()).then_some(&values));
+                lines.push(format!("{}: {} -> {}", key, describe(old), describe(new)));
+            }
+        }
+
+        let picture_types: BTreeSet<u32> = self.pictures.iter().chain(&after.pictures).map(|p| p.picture_type).collect();
+        for picture_type in picture_types {
+            let find = |tag: &Tag| tag.pictures.iter().find(|p| p.picture_type == picture_type).cloned();
+            let (old, new) = (find(self), find(after));
+            if old != new {
+                lines.push(format!(
+                    "Picture ({}): {} -> {}",
+                    picture_type_name(picture_type),
+                    describe_picture(old.as_ref()),
+                    describe_picture(new.as_ref())
+                ));
+            }
+        }
+
+        lines
+    }
+}
+
+fn describe_values(values: Option<&Vec<String>>) -> String {
+    match values {
+        Some(values) => values.iter().map(|value| format!("'{}'", value)).collect::<Vec<_>>().join("; "),
+        None => "(none)".to_string(),
+    }
+}
+
+fn describe_picture(picture: Option<&Picture>) -> String {
+    match picture {
+        Some(picture) => format!(
+            "{} {}x{}, {} bytes", picture.mime_type, picture.width, picture.height, picture.data.len()
+        ),
+        None => "(none)".to_string(),
+    }
+}
+
+/// Changes to apply to an existing tag, so each file is read and written once
+/// no matter how many fields are being set
+#[derive(Debug, Clone, Default)]
+pub struct TagUpdate {
+    pub fields: BTreeMap<Field, Vec<String>>,
+    /// Custom tags to set, replacing every existing value of the same key
+    pub custom: Vec<(String, String)>,
+    /// Fields to remove
+    pub remove_fields: BTreeSet<Field>,
+    /// Custom tag keys to remove (case-insensitive)
+    pub remove_custom: Vec<String>,
+    pub remove_pictures: bool,
+    /// Picture types to remove, e.g. `Picture::FRONT_COVER`
+    pub remove_picture_types: BTreeSet<u32>,
+    /// Remove everything but these items before making the other changes;
+    /// an empty list strips the tag completely
+    pub keep: Option<Vec<TagItem>>,
+    pub pictures: Vec<Picture>,
+    /// Pictures to embed only if the tag has no pictures yet, such as a
+    /// cover found next to the file
+    pub fallback_pictures: Vec<Picture>,
+}
+
+impl TagUpdate {
+    pub fn set(&mut self, field: Field, value: impl Into<String>) {
+        self.fields.insert(field, vec![value.into()]);
+    }
+
+    /// Set several values for a field, e.g. two artists
+    pub fn set_values(&mut self, field: Field, values: Vec<String>) {
+        self.fields.insert(field, values);
+    }
+
+    /// Set a custom tag, adding to any values given for the same key
+    /// earlier in this update
+    pub fn set_custom(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.custom.push((key.into(), value.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+            && self.custom.is_empty()
+            && self.remove_fields.is_empty()
+            && self.remove_custom.is_empty()
+            && !self.remove_pictures
+            && self.remove_picture_types.is_empty()
+            && self.keep.is_none()
+            && self.pictures.is_empty()
+            && self.fallback_pictures.is_empty()
+    }
+
+    /// Apply the changes on top of `tag`. Removals come first, so a field or
+    /// key that is both removed and set ends up with the new value.
+    pub fn apply(&self, tag: &mut Tag) {
+        let had_pictures = !tag.pictures.is_empty();
+        if let Some(keep) = &self.keep {
+            tag.fields.retain(|field, _| keep.contains(&TagItem::Field(*field)));
+            tag.custom.retain(|(key, _)| keep.iter().any(|item| item.matches_custom(key)));
+            if !keep.contains(&TagItem::Pictures) {
+                tag.pictures.clear();
+            }
+        }
+        if self.remove_pictures {
+            tag.pictures.clear();
+        }
+        tag.pictures.retain(|picture| !self.remove_picture_types.contains(&picture.picture_type));
+        for field in &self.remove_fields {
+            tag.fields.remove(field);
+        }
+        let replaced = self.remove_custom.iter().chain(self.custom.iter().map(|(key, _)| key));
+        let replaced: BTreeSet<String> = replaced.map(|key| key.to_uppercase()).collect();
+        tag.custom.retain(|(key, _)| !replaced.contains(&key.to_uppercase()));
+        tag.custom.extend(self.custom.iter().cloned());
+
+        for (field, values) in &self.fields {
+            tag.fields.insert(*field, values.clone());
+        }
+        for picture in &self.pictures {
+            tag.set_picture(picture.clone());
+        }
+        if !had_pictures {
+            for picture in &self.fallback_pictures {
+                tag.set_picture(picture.clone());
+            }
+        }
+    }
+}
+
+/// Parse a custom tag assignment `KEY=VALUE`. Keys are upper-cased and must
+/// be valid Vorbis comment names: printable ASCII without `=`.
+pub fn parse_tag_assignment(text: &str) -> Result<(String, String)> {
+    let (key, value) = text.split_once('=')
+        .ok_or_else(|| Error::InvalidInput(format!("Invalid tag '{}': expected KEY=VALUE", text)))?;
+    Ok((parse_tag_key(key)?, value.to_string()))
+}
+
+/// Check a custom tag key and upper-case it
+pub fn parse_tag_key(key: &str) -> Result<String> {
+    if key.is_empty() || !key.chars().all(|c| (' '..='}').contains(&c) && c != '=') {
+        return Err(Error::InvalidInput(format!("Invalid tag key '{}': use printable ASCII characters other than '='", key)));
+    }
+    Ok(key.to_uppercase())
+}
+
+/// Check a release date: a year, optionally followed by a month, day and
+/// time, e.g. `1999`, `1999-03` or `1999-03-24T12:00`
+pub fn parse_date(text: &str) -> Result<String> {
+    let date = Regex::new(r"^\d{4}(-\d{2}(-\d{2}(T\d{2}(:\d{2}(:\d{2})?)?)?)?)?$").unwrap();
+    if date.is_match(text.trim()) {
+        Ok(text.trim().to_string())
+    } else {
+        Err(Error::InvalidInput(format!("Invalid date '{}': expected YYYY, YYYY-MM or YYYY-MM-DD", text)))
+    }
+}
+
+/// Check an International Standard Recording Code and normalise it to the
+/// 12 upper-case characters tags store, e.g. `us-s1z-99-00001` -> `USS1Z9900001`
+pub fn parse_isrc(text: &str) -> Result<String> {
+    let isrc: String = text.trim().chars().filter(|&c| c != '-').collect::<String>().to_uppercase();
+    let valid = Regex::new(r"^[A-Z]{2}[A-Z0-9]{3}\d{7}$").unwrap();
+    if valid.is_match(&isrc) {
+        Ok(isrc)
+    } else {
+        Err(Error::InvalidInput(format!("Invalid ISRC '{}': expected e.g. USS1Z9900001", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let mut before = Tag::default();
+        before.fields.insert(Field::Album, vec!["Old".to_string()]);
+        before.fields.insert(Field::Artist, vec!["Same".to_string()]);
+        before.fields.insert(Field::Genre, vec!["Gone".to_string()]);
+        before.custom.push(("CATALOGNUMBER".to_string(), "A-1".to_string()));
+        before.custom.push(("Label".to_string(), "Old Label".to_string()));
+        let mut after = before.clone();
+        let mut update = TagUpdate::default();
+        update.set(Field::Album, "New");
+        update.set(Field::TrackNumber, "1");
+        update.remove_fields.insert(Field::Genre);
+        update.set_custom("catalognumber", "B-1");
+        update.set_custom("catalognumber", "B-2");
+        update.remove_custom.push("LABEL".to_string());
+        update.pictures.push(Picture {
+            picture_type: Picture::FRONT_COVER,
+            mime_type: "image/png".to_string(),
+            description: String::new(),
+            width: 2,
+            height: 2,
+            color_depth: 24,
+            colors: 0,
+            data: vec![0; 10],
+        });
+        update.apply(&mut after);
+
+        assert_eq!(before.diff(&after), [
+            "Album: 'Old' -> 'New'",
+            "Track: (none) -> '1'",
+            "Genre: 'Gone' -> (none)",
+            "CATALOGNUMBER: 'A-1' -> 'B-1'; 'B-2'",
+            "LABEL: 'Old Label' -> (none)",
+            "Picture (front cover): (none) -> image/png 2x2, 10 bytes",
+        ]);
+        assert!(after.diff(&after).is_empty());
+
+        // A fallback picture only goes into a tag without pictures
+        let fallback = TagUpdate { fallback_pictures: update.pictures.clone(), ..TagUpdate::default() };
+        let mut with_picture = after.clone();
+        fallback.apply(&mut with_picture);
+        assert_eq!(with_picture, after);
+        let mut without_picture = before.clone();
+        fallback.apply(&mut without_picture);
+        assert_eq!(without_picture.pictures, update.pictures);
+    }
+}
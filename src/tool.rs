@@ -0,0 +1,51 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::{Error, Result};
+
+/// Run an external program to completion on `path`, capturing its standard
+/// error so failures can be reported with the program's own message
+pub(crate) fn run(tool: &str, args: &[&str], path: &Path) -> Result<()> {
+    output(tool, args, path).map(|_| ())
+}
+
+/// Like `run`, but return what the program printed to standard output
+pub(crate) fn output(tool: &str, args: &[&str], path: &Path) -> Result<String> {
+    let output = Command::new(tool)
+        .args(args)
+        .output()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::MissingTool { tool: tool.to_string() },
+            _ => Error::Io { path: path.to_path_buf(), source: e },
+        })?;
+
+    if !output.status.success() {
+        return Err(Error::ToolFailed {
+            tool: tool.to_string(),
+            path: path.to_path_buf(),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_reports_missing_tool_and_failure() {
+        let path = Path::new("input.flac");
+        assert!(matches!(
+            run("audio-metadata-no-such-tool", &[], path),
+            Err(Error::MissingTool { tool }) if tool == "audio-metadata-no-such-tool"
+        ));
+        assert!(matches!(
+            run("sh", &["-c", "echo broken >&2; exit 3"], path),
+            Err(Error::ToolFailed { code: Some(3), stderr, .. }) if stderr == "broken"
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// How symbolic links are treated when collecting files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Ignore symbolic links entirely
+    Skip,
+    /// Process links to files, but do not descend into linked directories
+    #[default]
+    Files,
+    /// Process links to files and descend into linked directories
+    Follow,
+}
+
+/// Which files inside a directory are processed
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Descend into subdirectories
+    pub recursive: bool,
+    /// Deepest subdirectory level to descend into, where 1 is the directory's
+    /// immediate children. `None` means no limit.
+    pub max_depth: Option<usize>,
+    pub symlinks: SymlinkPolicy,
+    /// Only process files whose path relative to the directory matches one of
+    /// these globs. Every file matches if the list is empty.
+    pub include: Vec<String>,
+    /// Skip files and directories whose relative path matches one of these globs
+    pub exclude: Vec<String>,
+}
+
+/// Check whether a path is a regular file with one of the given extensions (case-insensitive)
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.is_file()
+        && path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Collect the files with one of the given extensions under `root`, grouped by
+/// the directory that contains them. Groups are in depth-first order and the
+/// files in each group are sorted by name.
+pub fn collect_file_groups(root: &Path, extensions: &[&str], options: &WalkOptions) -> Result<Vec<Vec<PathBuf>>> {
+    let walker = Walker {
+        root,
+        extensions,
+        options,
+        include: build_globs(&options.include)?,
+        exclude: build_globs(&options.exclude)?,
+    };
+    let mut visited = HashSet::new();
+    visited.insert(fs::canonicalize(root).map_err(Error::io(root))?);
+
+    let mut groups = Vec::new();
+    walker.walk(root, 0, &mut visited, &mut groups)?;
+    Ok(groups)
+}
+
+fn build_globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|e| Error::InvalidInput(format!("Invalid glob pattern '{}': {}", pattern, e)))?;
+        builder.add(glob);
+    }
+    builder.build()
+        .map_err(|e| Error::InvalidInput(format!("Invalid glob patterns: {}", e)))
+}
+
+struct Walker<'a> {
+    root: &'a Path,
+    extensions: &'a [&'a str],
+    options: &'a WalkOptions,
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl Walker<'_> {
+    fn walk(
+        &self,
+        dir: &Path,
+        depth: usize,
+        visited: &mut HashSet<PathBuf>,
+        groups: &mut Vec<Vec<PathBuf>>
+    ) -> Result<()> {
+        let descend = self.options.recursive && self.options.max_depth.is_none_or(|max| depth < max);
+        let mut files = Vec::new();
+        let mut subdirs = Vec::new();
+
+        for entry in fs::read_dir(dir).map_err(Error::io(dir))? {
+            let entry = entry.map_err(Error::io(dir))?;
+            let path = entry.path();
+            let is_symlink = entry.file_type().map_err(Error::io(&path))?.is_symlink();
+            if is_symlink && self.options.symlinks == SymlinkPolicy::Skip {
+                continue;
+            }
+            let relative = path.strip_prefix(self.root).unwrap_or(&path);
+            if self.exclude.is_match(relative) {
+                continue;
+            }
+
+            if path.is_dir() {
+                if descend && (!is_symlink || self.options.symlinks == SymlinkPolicy::Follow) {
+                    subdirs.push(path);
+                }
+            } else if has_extension(&path, self.extensions)
+                && (self.include.is_empty() || self.include.is_match(relative)) {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+        subdirs.sort();
+        if !files.is_empty() {
+            groups.push(files);
+        }
+
+        for subdir in subdirs {
+            // Followed links can lead back to a directory that was already walked
+            let canonical = fs::canonicalize(&subdir).map_err(Error::io(&subdir))?;
+            if visited.insert(canonical) {
+                self.walk(&subdir, depth + 1, visited, groups)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_file_groups() {
+        let root = std::env::temp_dir().join(format!("audio-metadata-walk-{}", std::process::id()));
+        for dir in ["Artist/Album 1", "Artist/Album 2/Bonus", "Artist/Live"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "loose.mp3",
+            "Artist/Album 1/02.flac",
+            "Artist/Album 1/01.mp3",
+            "Artist/Album 1/cover.jpg",
+            "Artist/Album 2/01.flac",
+            "Artist/Album 2/Bonus/01.flac",
+            "Artist/Live/01.flac",
+        ] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("Artist"), root.join("Artist/Album 1/loop")).unwrap();
+
+        let collect = |options: &WalkOptions| -> Vec<Vec<String>> {
+            collect_file_groups(&root, &["mp3", "flac"], options).unwrap().iter()
+                .map(|group| group.iter()
+                    .map(|path| path.strip_prefix(&root).unwrap().to_string_lossy().into_owned())
+                    .collect())
+                .collect()
+        };
+
+        assert_eq!(collect(&WalkOptions::default()), vec![vec!["loose.mp3"]]);
+
+        let options = WalkOptions {
+            recursive: true,
+            symlinks: SymlinkPolicy::Follow,
+            exclude: vec!["Artist/Live".to_string()],
+            ..WalkOptions::default()
+        };
+        assert_eq!(collect(&options), vec![
+            vec!["loose.mp3"],
+            vec!["Artist/Album 1/01.mp3", "Artist/Album 1/02.flac"],
+            vec!["Artist/Album 2/01.flac"],
+            vec!["Artist/Album 2/Bonus/01.flac"],
+        ]);
+
+        let options = WalkOptions {
+            recursive: true,
+            max_depth: Some(2),
+            include: vec!["*.flac".to_string()],
+            ..WalkOptions::default()
+        };
+        assert_eq!(collect(&options), vec![
+            vec!["Artist/Album 1/02.flac"],
+            vec!["Artist/Album 2/01.flac"],
+            vec!["Artist/Live/01.flac"],
+        ]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}